@@ -0,0 +1,102 @@
+use crate::geneids::strip_version;
+use crate::input::{expand_path, open_input};
+use crate::reader::Record;
+use log::*;
+use std::collections::HashMap;
+use std::io::prelude::*;
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+
+// The numbers of features aggregated:
+pub struct AggregateSummary {
+    pub n_mapped: usize,
+    pub n_genes: usize,
+    pub n_unmapped: usize,
+}
+
+// Read a tab-separated file mapping features (first column) to genes (second column). If
+// requested, version suffixes are removed from all of the IDs:
+pub fn read_mapping(path: &Path, strip_versions: bool) -> Result<HashMap<String, String>, Error> {
+    let filename = match expand_path(path) {
+        Some(f) => f,
+        None => {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                "aggregation mapping not found",
+            ))
+        }
+    };
+    info!(
+        "{}",
+        format!("reading feature to gene mapping from {}", filename)
+    );
+    let mut mapping = HashMap::new();
+    for line in open_input(&filename)?.lines() {
+        let line = line?;
+        let line_trimmed = line.trim();
+        if line_trimmed.is_empty() || line_trimmed.starts_with('#') {
+            continue;
+        }
+        let line_data: Vec<_> = line_trimmed.split('\t').collect();
+        if line_data.len() < 2 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("no gene given for feature {} in mapping", line_data[0]),
+            ));
+        }
+        let (feature, gene) = if strip_versions {
+            (strip_version(line_data[0]), strip_version(line_data[1]))
+        } else {
+            (line_data[0], line_data[1])
+        };
+        mapping.insert(String::from(feature), String::from(gene));
+    }
+    Ok(mapping)
+}
+
+// Sum the rows of the features mapped to each gene. Each gene takes the position (and any
+// annotation) of its first feature. Unmapped features are kept unchanged, or dropped:
+pub fn aggregate(
+    records: Vec<Record>,
+    mapping: &HashMap<String, String>,
+    keep_unmapped: bool,
+) -> (Vec<Record>, AggregateSummary) {
+    let mut aggregated: Vec<Record> = Vec::with_capacity(records.len());
+    let mut index: HashMap<String, usize> = HashMap::new();
+    let mut summary = AggregateSummary {
+        n_mapped: 0,
+        n_genes: 0,
+        n_unmapped: 0,
+    };
+    for mut record in records {
+        let gene = match mapping.get(&record.gene) {
+            Some(g) => g,
+            None => {
+                debug!(
+                    "{}",
+                    format!("feature {} is not mapped to a gene", record.gene)
+                );
+                summary.n_unmapped += 1;
+                if keep_unmapped {
+                    aggregated.push(record);
+                }
+                continue;
+            }
+        };
+        summary.n_mapped += 1;
+        match index.get(gene) {
+            Some(i) => {
+                for (c, v) in aggregated[*i].counts.iter_mut().zip(record.counts.iter()) {
+                    *c += v;
+                }
+            }
+            None => {
+                index.insert(gene.clone(), aggregated.len());
+                record.gene = gene.clone();
+                aggregated.push(record);
+            }
+        }
+    }
+    summary.n_genes = index.len();
+    (aggregated, summary)
+}
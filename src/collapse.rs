@@ -0,0 +1,87 @@
+use crate::input::{expand_path, open_input};
+use log::*;
+use regex::Regex;
+use std::collections::HashMap;
+use std::io::prelude::*;
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+
+// How input columns are mapped to merged samples:
+pub enum Collapse {
+    Table(HashMap<String, String>),
+    Pattern(Regex),
+}
+
+impl Collapse {
+    // Read a tab-separated file mapping columns (first column) to merged samples (second column):
+    pub fn read(path: &Path) -> Result<Collapse, Error> {
+        let filename = match expand_path(path) {
+            Some(f) => f,
+            None => return Err(Error::new(ErrorKind::NotFound, "collapse file not found")),
+        };
+        info!("{}", format!("reading column collapsing from {}", filename));
+        let mut table = HashMap::new();
+        for line in open_input(&filename)?.lines() {
+            let line = line?;
+            let line_trimmed = line.trim();
+            if line_trimmed.is_empty() || line_trimmed.starts_with('#') {
+                continue;
+            }
+            let line_data: Vec<_> = line_trimmed.split('\t').collect();
+            if line_data.len() < 2 {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("no merged sample given for column {}", line_data[0]),
+                ));
+            }
+            table.insert(String::from(line_data[0]), String::from(line_data[1]));
+        }
+        Ok(Collapse::Table(table))
+    }
+
+    // The merged sample for a column. Columns missing from a table are left as they are. For a
+    // pattern, the merged sample is the first capture group if the pattern has one, and
+    // otherwise the column name with the match removed:
+    fn merged_name(&self, column: &str) -> String {
+        match self {
+            Collapse::Table(table) => table
+                .get(column)
+                .cloned()
+                .unwrap_or_else(|| String::from(column)),
+            Collapse::Pattern(pattern) => match pattern.captures(column) {
+                Some(c) => match c.get(1) {
+                    Some(group) => String::from(group.as_str()),
+                    None => pattern.replace(column, "").into_owned(),
+                },
+                None => String::from(column),
+            },
+        }
+    }
+
+    // Map a set of columns to merged samples, returning the merged sample names (in order of
+    // first appearance) & the index of each column's merged sample:
+    pub fn groups(&self, columns: &[String]) -> (Vec<String>, Vec<usize>) {
+        let mut names: Vec<String> = Vec::new();
+        let mut indices = Vec::with_capacity(columns.len());
+        for column in columns {
+            let name = self.merged_name(column);
+            match names.iter().position(|n| *n == name) {
+                Some(i) => indices.push(i),
+                None => {
+                    indices.push(names.len());
+                    names.push(name);
+                }
+            }
+        }
+        (names, indices)
+    }
+}
+
+// Sum a row of values into the merged samples:
+pub fn sum_columns(values: &[f64], indices: &[usize], n_merged: usize) -> Vec<f64> {
+    let mut merged = vec![0.0; n_merged];
+    for (v, i) in values.iter().zip(indices.iter()) {
+        merged[*i] += v;
+    }
+    merged
+}
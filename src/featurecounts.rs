@@ -0,0 +1,124 @@
+use crate::reader::{CountMode, CountsReader, MatrixRecords, Record};
+use std::io::prelude::*;
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+
+// The final annotation column of a featureCounts table (any extra attributes follow it):
+const LAST_ANNOTATION_COLUMN: &str = "Length";
+
+// Find the summary file written alongside a featureCounts table (allowing for either being compressed):
+pub fn find_summary(filename: &str) -> Option<String> {
+    let base = filename.trim_end_matches(".gz");
+    vec![
+        format!("{}.summary", filename),
+        format!("{}.summary", base),
+        format!("{}.summary.gz", base),
+    ]
+    .into_iter()
+    .find(|f| Path::new(f).is_file())
+}
+
+// Read a featureCounts summary file, returning its rows as metacount records:
+fn read_summary(
+    summary: Box<dyn BufRead>,
+    samples: &[String],
+    mode: CountMode,
+) -> Result<Vec<Record>, Error> {
+    let mut lines = summary.lines();
+    let header = match lines.next() {
+        Some(Ok(h)) => h,
+        _ => {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "failed to read featureCounts summary header",
+            ))
+        }
+    };
+    if !header
+        .trim()
+        .split('\t')
+        .skip(1)
+        .eq(samples.iter().map(|s| s.as_str()))
+    {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "featureCounts summary samples do not match the counts file",
+        ));
+    }
+    let mut metacounts = Vec::new();
+    for line in lines {
+        let line = line?;
+        let line_data: Vec<_> = line.trim().split('\t').collect();
+        let counts: Vec<_> = match line_data.iter().skip(1).map(|s| mode.parse(s)).collect() {
+            Some(c) => c,
+            None => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "failed to convert featureCounts summary line {}",
+                        line.trim()
+                    ),
+                ))
+            }
+        };
+        metacounts.push(Record {
+            gene: format!("__{}", line_data[0]),
+            annotation: Vec::new(),
+            counts,
+        });
+    }
+    Ok(metacounts)
+}
+
+// Read a featureCounts output table. The annotation columns are kept, and the rows of
+// the (optional) summary file are appended as metacounts:
+pub fn read_featurecounts(
+    input: Box<dyn BufRead>,
+    summary: Option<Box<dyn BufRead>>,
+    mode: CountMode,
+) -> Result<CountsReader, Error> {
+    let mut lines = input.lines();
+
+    // Skip the leading comment line(s) and read the header:
+    let file_header = loop {
+        match lines.next() {
+            Some(Ok(h)) if h.starts_with('#') => continue,
+            Some(Ok(h)) => break h,
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::UnexpectedEof,
+                    "failed to read featureCounts header",
+                ))
+            }
+        }
+    };
+    let header_data: Vec<String> = file_header.trim().split('\t').map(String::from).collect();
+    let n_annotation = match header_data.iter().position(|c| c == LAST_ANNOTATION_COLUMN) {
+        Some(i) => i,
+        None => {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "featureCounts header has no Length column",
+            ))
+        }
+    };
+    let samples: Vec<String> = header_data[n_annotation + 1..].to_vec();
+
+    let metacounts = match summary {
+        Some(s) => read_summary(s, &samples, mode)?,
+        None => Vec::new(),
+    };
+    let records = MatrixRecords {
+        lines,
+        n_annotation,
+        n_samples: samples.len(),
+        mode,
+    };
+
+    Ok(CountsReader {
+        id_column: header_data[0].clone(),
+        annotation_columns: header_data[1..=n_annotation].to_vec(),
+        samples,
+        records: Box::new(records.chain(metacounts.into_iter().map(Ok))),
+    })
+}
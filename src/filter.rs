@@ -0,0 +1,535 @@
+use crate::genelist::GeneList;
+use crate::gtf::Gene;
+use crate::stats;
+use log::*;
+use std::collections::HashMap;
+use std::str::FromStr;
+
+// The tolerance used by edgeR when comparing against the filterByExpr cutoffs:
+const FILTER_BY_EXPR_TOL: f64 = 1e-14;
+
+// The tolerance used when resolving a proportion of samples to a number of samples:
+const PROPORTION_TOL: f64 = 1e-9;
+
+// Parse a proportion, given either as a fraction (such as 0.25) or a percentage (such as 25%):
+pub fn parse_proportion(s: &str) -> Result<f64, String> {
+    let p = match s.strip_suffix('%') {
+        Some(pct) => pct.parse::<f64>().map(|p| p / 100.0),
+        None => s.parse::<f64>(),
+    }
+    .map_err(|_| format!("invalid proportion {}", s))?;
+    if !(0.0..=1.0).contains(&p) {
+        return Err(format!("proportion {} is not between 0 and 1", s));
+    }
+    Ok(p)
+}
+
+// A number of samples, given either as an absolute number or as a proportion of the samples:
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SampleCount {
+    Absolute(u64),
+    Proportion(f64),
+}
+
+impl SampleCount {
+    // Resolve to a number of samples out of the given total, rounding proportions up:
+    pub fn resolve(self, n_samples: usize) -> u64 {
+        match self {
+            SampleCount::Absolute(n) => n,
+            SampleCount::Proportion(p) => {
+                (p * n_samples as f64 - PROPORTION_TOL).ceil().max(0.0) as u64
+            }
+        }
+    }
+}
+
+impl FromStr for SampleCount {
+    type Err = String;
+    // Whole numbers are absolute; fractions (such as 0.25) and percentages (such as 25%) are proportions:
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.ends_with('%') || s.contains('.') {
+            parse_proportion(s).map(SampleCount::Proportion)
+        } else {
+            s.parse::<u64>()
+                .map(SampleCount::Absolute)
+                .map_err(|_| format!("invalid number of samples {}", s))
+        }
+    }
+}
+
+// The scale on which the variance filters are calculated:
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ValueScale {
+    Raw,
+    Cpm,
+}
+
+impl ValueScale {
+    pub const NAMES: &'static [&'static str] = &["raw", "cpm"];
+}
+
+impl FromStr for ValueScale {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "raw" => Ok(ValueScale::Raw),
+            "cpm" => Ok(ValueScale::Cpm),
+            _ => Err(format!("unknown value scale {}", s)),
+        }
+    }
+}
+
+// The filters that can reject a gene:
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FilterReason {
+    GeneList,
+    Biotype,
+    Chromosome,
+    GeneLength,
+    MinCount,
+    MinExpressed,
+    MinCpm,
+    FilterByExpr,
+    MinGroupExpressed,
+    MinGroupProp,
+    MinVariance,
+    MinCv,
+    MinLogVariance,
+    ZeroVariance,
+    TopVariable,
+}
+
+impl FilterReason {
+    // The machine-readable name of the filter:
+    pub fn name(self) -> &'static str {
+        match self {
+            FilterReason::GeneList => "gene_list",
+            FilterReason::Biotype => "biotype",
+            FilterReason::Chromosome => "chromosome",
+            FilterReason::GeneLength => "gene_length",
+            FilterReason::MinCount => "min_count",
+            FilterReason::MinExpressed => "min_expressed",
+            FilterReason::MinCpm => "min_cpm",
+            FilterReason::FilterByExpr => "filter_by_expr",
+            FilterReason::MinGroupExpressed => "min_group_expressed",
+            FilterReason::MinGroupProp => "min_group_prop",
+            FilterReason::MinVariance => "min_variance",
+            FilterReason::MinCv => "min_cv",
+            FilterReason::MinLogVariance => "min_log_variance",
+            FilterReason::ZeroVariance => "zero_variance",
+            FilterReason::TopVariable => "top_variable",
+        }
+    }
+}
+
+// Format a set of filter reasons for output:
+pub fn format_reasons(reasons: &[FilterReason]) -> String {
+    reasons
+        .iter()
+        .map(|r| r.name())
+        .collect::<Vec<&str>>()
+        .join(",")
+}
+
+// The parameters of edgeR's filterByExpr:
+#[derive(Debug, Clone, Copy)]
+pub struct FilterByExpr {
+    pub min_count: f64,
+    pub min_total_count: f64,
+    pub large_n: f64,
+    pub min_prop: f64,
+}
+
+// The cutoffs resolved from the filterByExpr parameters, library sizes & groups:
+#[derive(Debug, Clone, Copy)]
+pub struct FilterByExprCutoffs {
+    pub cpm: f64,
+    pub min_samples: f64,
+    pub min_total_count: f64,
+}
+
+impl FilterByExpr {
+    // Resolve the cutoffs in the same way as edgeR::filterByExpr (with a group factor). The
+    // minimum sample size is that of the smallest group, reduced for large groups:
+    pub fn cutoffs(&self, lib_sizes: &[f64], group_sizes: &[usize]) -> FilterByExprCutoffs {
+        let mut min_samples = group_sizes.iter().copied().min().unwrap_or(0) as f64;
+        if min_samples > self.large_n {
+            min_samples = self.large_n + (min_samples - self.large_n) * self.min_prop;
+        }
+        FilterByExprCutoffs {
+            cpm: stats::cpm(self.min_count, stats::median(lib_sizes)),
+            min_samples,
+            min_total_count: self.min_total_count,
+        }
+    }
+}
+
+// The per-gene filters. Filters based on CPM or sample groups use the library sizes and
+// groups, which must be set (with prepare) before any genes are tested:
+pub struct GeneFilter {
+    pub expression_threshold: f64,
+    pub min_count: Option<f64>,
+    pub min_expressed: Option<SampleCount>,
+    pub min_cpm: Option<(f64, SampleCount)>,
+    pub filter_by_expr: Option<FilterByExpr>,
+    pub min_group_expressed: Option<SampleCount>,
+    pub min_group_prop: Option<f64>,
+    pub variance_scale: ValueScale,
+    pub min_variance: Option<f64>,
+    pub min_cv: Option<f64>,
+    pub min_log_variance: Option<f64>,
+    pub pseudocount: f64,
+    pub filter_identical: bool,
+    pub gene_list: GeneList,
+    pub annotation: Option<HashMap<String, Gene>>,
+    pub keep_biotypes: Vec<String>,
+    pub exclude_chroms: Vec<String>,
+    pub min_gene_length: Option<u64>,
+    pub lib_sizes: Vec<f64>,
+    pub groups: Vec<usize>,
+    pub group_sizes: Vec<usize>,
+    pub filter_by_expr_cutoffs: Option<FilterByExprCutoffs>,
+}
+
+// The per-gene statistics used for filtering:
+pub struct GeneStats {
+    pub n_samples: usize,
+    pub total: f64,
+    pub n_expressed: u64,
+    pub identical: bool,
+}
+
+impl GeneFilter {
+    // Set the library sizes & (indexed) sample groups used by the CPM & group filters:
+    pub fn prepare(&mut self, lib_sizes: Vec<f64>, groups: Vec<usize>) {
+        let mut group_sizes = vec![0; groups.iter().max().map_or(0, |g| g + 1)];
+        for g in groups.iter() {
+            group_sizes[*g] += 1;
+        }
+        self.filter_by_expr_cutoffs = self
+            .filter_by_expr
+            .map(|f| f.cutoffs(&lib_sizes, &group_sizes));
+        if let Some(c) = self.filter_by_expr_cutoffs {
+            info!(
+                "{}",
+                format!(
+                    "filterByExpr CPM cutoff {}, minimum sample size {}",
+                    c.cpm, c.min_samples
+                )
+            );
+        }
+        self.lib_sizes = lib_sizes;
+        self.groups = groups;
+        self.group_sizes = group_sizes;
+    }
+
+    // Whether any of the group filters are in use:
+    pub fn has_group_filters(&self) -> bool {
+        self.min_group_expressed.is_some() || self.min_group_prop.is_some()
+    }
+
+    // Count the expressed samples in each group:
+    fn group_expressed(&self, counts: &[f64]) -> Vec<u64> {
+        let mut n_expressed = vec![0; self.group_sizes.len()];
+        for (v, g) in counts.iter().zip(self.groups.iter()) {
+            if *v >= self.expression_threshold {
+                n_expressed[*g] += 1;
+            }
+        }
+        n_expressed
+    }
+
+    // Test whether a group (with the given number of expressed samples) meets the
+    // --min-group-expressed & --min-group-prop criteria respectively:
+    fn group_meets_expressed(&self, n_expressed: u64, size: usize) -> bool {
+        self.min_group_expressed
+            .is_none_or(|m| n_expressed >= m.resolve(size))
+    }
+
+    fn group_meets_prop(&self, n_expressed: u64, size: usize) -> bool {
+        self.min_group_prop
+            .is_none_or(|p| n_expressed >= SampleCount::Proportion(p).resolve(size))
+    }
+
+    // Test whether a gene meets the group filter criteria within each group:
+    pub fn group_passes(&self, counts: &[f64]) -> Vec<bool> {
+        self.group_expressed(counts)
+            .iter()
+            .zip(self.group_sizes.iter())
+            .map(|(n, size)| {
+                self.group_meets_expressed(*n, *size) && self.group_meets_prop(*n, *size)
+            })
+            .collect()
+    }
+
+    // Scale a gene's counts for the variance filters:
+    fn scale(&self, counts: &[f64]) -> Vec<f64> {
+        match self.variance_scale {
+            ValueScale::Raw => counts.to_vec(),
+            ValueScale::Cpm => counts
+                .iter()
+                .zip(self.lib_sizes.iter())
+                .map(|(v, l)| stats::cpm(*v, *l))
+                .collect(),
+        }
+    }
+
+    // Count the samples with a CPM of at least the given cutoff:
+    fn n_cpm_expressed(&self, counts: &[f64], cutoff: f64) -> u64 {
+        counts
+            .iter()
+            .zip(self.lib_sizes.iter())
+            .filter(|(v, l)| stats::cpm(**v, **l) >= cutoff)
+            .count() as u64
+    }
+
+    // Test a gene against every filter, returning the filters that it failed:
+    pub fn test(&self, gene: &str, counts: &[f64], stats: &GeneStats) -> Vec<FilterReason> {
+        let mut reasons = Vec::new();
+
+        // Filter on the gene lists:
+        if self.gene_list.is_excluded(gene) {
+            debug!(
+                "{}",
+                format!("gene {} failed filtering (removed by gene lists)", gene)
+            );
+            reasons.push(FilterReason::GeneList);
+        }
+
+        // Filter on the gene annotation (removing genes without the required annotation):
+        if let Some(ref annotation) = self.annotation {
+            let annotation = annotation.get(gene);
+            if !self.keep_biotypes.is_empty()
+                && !annotation
+                    .and_then(|a| a.biotype.as_ref())
+                    .is_some_and(|b| self.keep_biotypes.contains(b))
+            {
+                debug!("{}", format!("gene {} failed filtering (biotype)", gene));
+                reasons.push(FilterReason::Biotype);
+            }
+            if annotation.is_some_and(|a| self.exclude_chroms.contains(&a.chrom)) {
+                debug!("{}", format!("gene {} failed filtering (chromosome)", gene));
+                reasons.push(FilterReason::Chromosome);
+            }
+            if let Some(min_gene_length) = self.min_gene_length {
+                if annotation.is_none_or(|a| a.length() < min_gene_length) {
+                    debug!(
+                        "{}",
+                        format!("gene {} failed filtering (gene length)", gene)
+                    );
+                    reasons.push(FilterReason::GeneLength);
+                }
+            }
+        }
+
+        // Filter by minimum count:
+        match self.min_count {
+            Some(min_count) if stats.total < min_count => {
+                debug!(
+                    "{}",
+                    format!(
+                        "gene {} failed filtering (total count {} < {})",
+                        gene, stats.total, min_count
+                    )
+                );
+                reasons.push(FilterReason::MinCount);
+            }
+            _ => (),
+        };
+
+        // Filter on zero count:
+        match self.min_expressed.map(|m| m.resolve(stats.n_samples)) {
+            Some(min_expressed) if stats.n_expressed < min_expressed => {
+                debug!(
+                    "{}",
+                    format!(
+                        "gene {} failed filtering (expressed count {} < {})",
+                        gene, stats.n_expressed, min_expressed
+                    )
+                );
+                reasons.push(FilterReason::MinExpressed);
+            }
+            _ => (),
+        }
+
+        // Filter on counts per million:
+        if let Some((min_cpm, min_samples)) = self.min_cpm {
+            let min_samples = min_samples.resolve(stats.n_samples);
+            let n_cpm_expressed = self.n_cpm_expressed(counts, min_cpm);
+            if n_cpm_expressed < min_samples {
+                debug!(
+                    "{}",
+                    format!(
+                        "gene {} failed filtering (samples with CPM >= {} {} < {})",
+                        gene, min_cpm, n_cpm_expressed, min_samples
+                    )
+                );
+                reasons.push(FilterReason::MinCpm);
+            }
+        }
+
+        // Filter as edgeR's filterByExpr:
+        if let Some(c) = self.filter_by_expr_cutoffs {
+            let n_cpm_expressed = self.n_cpm_expressed(counts, c.cpm) as f64;
+            if n_cpm_expressed < c.min_samples - FILTER_BY_EXPR_TOL
+                || stats.total < c.min_total_count - FILTER_BY_EXPR_TOL
+            {
+                debug!(
+                    "{}",
+                    format!(
+                        "gene {} failed filtering (filterByExpr samples with CPM >= {} {}, total count {})",
+                        gene, c.cpm, n_cpm_expressed, stats.total
+                    )
+                );
+                reasons.push(FilterReason::FilterByExpr);
+            }
+        }
+
+        // Filter on the expressed samples within groups:
+        if self.has_group_filters() {
+            let group_expressed = self.group_expressed(counts);
+            if let Some(min_group_expressed) = self.min_group_expressed {
+                if group_expressed
+                    .iter()
+                    .zip(self.group_sizes.iter())
+                    .all(|(n, size)| !self.group_meets_expressed(*n, *size))
+                {
+                    debug!(
+                        "{}",
+                        format!(
+                            "gene {} failed filtering (expressed count < {:?} in every group)",
+                            gene, min_group_expressed
+                        )
+                    );
+                    reasons.push(FilterReason::MinGroupExpressed);
+                }
+            }
+            if let Some(min_group_prop) = self.min_group_prop {
+                if group_expressed
+                    .iter()
+                    .zip(self.group_sizes.iter())
+                    .any(|(n, size)| !self.group_meets_prop(*n, *size))
+                {
+                    debug!(
+                        "{}",
+                        format!(
+                            "gene {} failed filtering (expressed proportion < {} in a group)",
+                            gene, min_group_prop
+                        )
+                    );
+                    reasons.push(FilterReason::MinGroupProp);
+                }
+            }
+        }
+
+        // Filter on variance, coefficient of variation & log variance:
+        if self.min_variance.is_some() || self.min_cv.is_some() || self.min_log_variance.is_some() {
+            let values = self.scale(counts);
+            if let Some(min_variance) = self.min_variance {
+                let v = stats::variance(&values);
+                if v.is_nan() || v < min_variance {
+                    debug!(
+                        "{}",
+                        format!(
+                            "gene {} failed filtering (variance {} < {})",
+                            gene, v, min_variance
+                        )
+                    );
+                    reasons.push(FilterReason::MinVariance);
+                }
+            }
+            if let Some(min_cv) = self.min_cv {
+                let cv = stats::variance(&values).sqrt() / stats::mean(&values);
+                if cv.is_nan() || cv < min_cv {
+                    debug!(
+                        "{}",
+                        format!(
+                            "gene {} failed filtering (coefficient of variation {} < {})",
+                            gene, cv, min_cv
+                        )
+                    );
+                    reasons.push(FilterReason::MinCv);
+                }
+            }
+            if let Some(min_log_variance) = self.min_log_variance {
+                let log_values: Vec<f64> = values
+                    .iter()
+                    .map(|v| (v + self.pseudocount).log2())
+                    .collect();
+                let v = stats::variance(&log_values);
+                if v.is_nan() || v < min_log_variance {
+                    debug!(
+                        "{}",
+                        format!(
+                            "gene {} failed filtering (log variance {} < {})",
+                            gene, v, min_log_variance
+                        )
+                    );
+                    reasons.push(FilterReason::MinLogVariance);
+                }
+            }
+        }
+
+        // Filter on zero variance:
+        if self.filter_identical && stats.identical {
+            debug!(
+                "{}",
+                format!("gene {} failed filtering (zero variance)", gene)
+            );
+            reasons.push(FilterReason::ZeroVariance);
+        }
+
+        reasons
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Parse a number of samples & resolve it against a total:
+    fn resolve(s: &str, n_samples: usize) -> u64 {
+        s.parse::<SampleCount>().unwrap().resolve(n_samples)
+    }
+
+    #[test]
+    fn whole_numbers_are_absolute() {
+        assert_eq!("1".parse::<SampleCount>(), Ok(SampleCount::Absolute(1)));
+        assert_eq!(resolve("1", 10), 1);
+        assert_eq!(resolve("0", 10), 0);
+    }
+
+    #[test]
+    fn decimals_and_percentages_are_proportions() {
+        assert_eq!(
+            "1.0".parse::<SampleCount>(),
+            Ok(SampleCount::Proportion(1.0))
+        );
+        assert_eq!(resolve("1.0", 3), 3);
+        assert_eq!(resolve("0.5", 4), 2);
+        assert_eq!(resolve("25%", 8), 2);
+    }
+
+    #[test]
+    fn proportions_round_up() {
+        assert_eq!(resolve("67%", 3), 3);
+        assert_eq!(resolve("0.5", 3), 2);
+        assert_eq!(resolve("0.01", 3), 1);
+    }
+
+    #[test]
+    fn proportions_tolerate_floating_point_error() {
+        // 0.28 * 25 is 7.000000000000001:
+        assert_eq!(resolve("0.28", 25), 7);
+        assert_eq!(resolve("0.3", 10), 3);
+    }
+
+    #[test]
+    fn invalid_counts_are_rejected() {
+        assert!("1.5".parse::<SampleCount>().is_err());
+        assert!("150%".parse::<SampleCount>().is_err());
+        assert!("-1".parse::<SampleCount>().is_err());
+        assert!("x".parse::<SampleCount>().is_err());
+        assert!("%".parse::<SampleCount>().is_err());
+    }
+}
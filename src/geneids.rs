@@ -0,0 +1,175 @@
+use crate::reader::Record;
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use std::str::FromStr;
+
+// How rows with duplicate gene IDs are handled:
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DuplicatePolicy {
+    Keep,
+    Error,
+    First,
+    Sum,
+    Max,
+}
+
+impl DuplicatePolicy {
+    pub const NAMES: &'static [&'static str] = &["keep", "error", "first", "sum", "max"];
+}
+
+impl FromStr for DuplicatePolicy {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "keep" => Ok(DuplicatePolicy::Keep),
+            "error" => Ok(DuplicatePolicy::Error),
+            "first" => Ok(DuplicatePolicy::First),
+            "sum" => Ok(DuplicatePolicy::Sum),
+            "max" => Ok(DuplicatePolicy::Max),
+            _ => Err(format!("unknown duplicate policy {}", s)),
+        }
+    }
+}
+
+// Remove a trailing version suffix (such as the .17 of ENSG00000141510.17) from a gene ID:
+pub fn strip_version(gene: &str) -> &str {
+    match gene.rsplit_once('.') {
+        Some((id, version))
+            if !id.is_empty()
+                && !version.is_empty()
+                && version.bytes().all(|b| b.is_ascii_digit()) =>
+        {
+            id
+        }
+        _ => gene,
+    }
+}
+
+// Collapse the rows sharing a gene ID according to the given policy, keeping each gene at the
+// position of its first row (and with that row's annotation). Returns the number of gene IDs
+// that were duplicated:
+pub fn collapse_duplicates(
+    records: Vec<Record>,
+    policy: DuplicatePolicy,
+) -> Result<(Vec<Record>, usize), Error> {
+    let mut collapsed: Vec<Record> = Vec::with_capacity(records.len());
+    let mut index: HashMap<String, (usize, bool)> = HashMap::with_capacity(records.len());
+    for record in records {
+        let i = match index.get_mut(&record.gene) {
+            Some(entry) => {
+                entry.1 = true;
+                entry.0
+            }
+            None => {
+                index.insert(record.gene.clone(), (collapsed.len(), false));
+                collapsed.push(record);
+                continue;
+            }
+        };
+        match policy {
+            DuplicatePolicy::Keep => collapsed.push(record),
+            DuplicatePolicy::Error => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("duplicate gene ID {}", record.gene),
+                ))
+            }
+            DuplicatePolicy::First => (),
+            DuplicatePolicy::Sum => {
+                for (c, v) in collapsed[i].counts.iter_mut().zip(record.counts.iter()) {
+                    *c += v;
+                }
+            }
+            DuplicatePolicy::Max => {
+                for (c, v) in collapsed[i].counts.iter_mut().zip(record.counts.iter()) {
+                    *c = c.max(*v);
+                }
+            }
+        }
+    }
+    let n_duplicated = index.values().filter(|(_, d)| *d).count();
+    Ok((collapsed, n_duplicated))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A record with the given ID, annotation & counts:
+    fn record(gene: &str, annotation: &str, counts: &[f64]) -> Record {
+        Record {
+            gene: String::from(gene),
+            annotation: vec![String::from(annotation)],
+            counts: counts.to_vec(),
+        }
+    }
+
+    // Collapse a set of duplicated records, returning the (ID, annotation, counts) of each row:
+    fn collapse(policy: DuplicatePolicy) -> (Vec<(String, String, Vec<f64>)>, usize) {
+        let records = vec![
+            record("g1", "first", &[1.0, 5.0]),
+            record("g2", "other", &[2.0, 2.0]),
+            record("g1", "second", &[3.0, 4.0]),
+        ];
+        let (collapsed, n_duplicated) = collapse_duplicates(records, policy).unwrap();
+        (
+            collapsed
+                .into_iter()
+                .map(|r| (r.gene, r.annotation[0].clone(), r.counts))
+                .collect(),
+            n_duplicated,
+        )
+    }
+
+    #[test]
+    fn strip_version_removes_numeric_suffixes() {
+        assert_eq!(strip_version("ENSG00000141510.17"), "ENSG00000141510");
+        assert_eq!(strip_version("ENST1.2.3"), "ENST1.2");
+    }
+
+    #[test]
+    fn strip_version_keeps_other_ids() {
+        assert_eq!(strip_version("ENSG00000141510"), "ENSG00000141510");
+        assert_eq!(strip_version("AC012345.1a"), "AC012345.1a");
+        assert_eq!(strip_version("GENE."), "GENE.");
+        assert_eq!(strip_version(".1"), ".1");
+    }
+
+    #[test]
+    fn keep_leaves_duplicates_in_place() {
+        let (rows, n_duplicated) = collapse(DuplicatePolicy::Keep);
+        assert_eq!(rows.len(), 3);
+        assert_eq!(n_duplicated, 1);
+    }
+
+    #[test]
+    fn first_keeps_the_first_row() {
+        let (rows, _) = collapse(DuplicatePolicy::First);
+        assert_eq!(
+            rows[0],
+            (String::from("g1"), String::from("first"), vec![1.0, 5.0])
+        );
+        assert_eq!(rows.len(), 2);
+    }
+
+    #[test]
+    fn sum_and_max_combine_at_the_first_position() {
+        let (rows, n_duplicated) = collapse(DuplicatePolicy::Sum);
+        assert_eq!(
+            rows[0],
+            (String::from("g1"), String::from("first"), vec![4.0, 9.0])
+        );
+        assert_eq!(rows[1].0, "g2");
+        assert_eq!(n_duplicated, 1);
+        let (rows, _) = collapse(DuplicatePolicy::Max);
+        assert_eq!(rows[0].2, vec![3.0, 5.0]);
+    }
+
+    #[test]
+    fn error_rejects_duplicates() {
+        let records = vec![record("g1", "", &[1.0]), record("g1", "", &[2.0])];
+        assert!(collapse_duplicates(records, DuplicatePolicy::Error).is_err());
+        let records = vec![record("g1", "", &[1.0]), record("g2", "", &[2.0])];
+        assert!(collapse_duplicates(records, DuplicatePolicy::Error).is_ok());
+    }
+}
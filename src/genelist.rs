@@ -0,0 +1,61 @@
+use crate::input::{expand_path, open_input};
+use log::*;
+use regex::Regex;
+use std::collections::HashSet;
+use std::io::prelude::*;
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+
+// Gene allow-lists & block-lists, given as files of gene IDs or regular expressions:
+#[derive(Default)]
+pub struct GeneList {
+    pub include: Option<HashSet<String>>,
+    pub exclude: HashSet<String>,
+    pub include_patterns: Vec<Regex>,
+    pub exclude_patterns: Vec<Regex>,
+}
+
+// Read a gene list file, with one gene ID (in the first column) per line. Comment lines are skipped:
+pub fn read_gene_ids(path: &Path) -> Result<HashSet<String>, Error> {
+    let filename = match expand_path(path) {
+        Some(f) => f,
+        None => return Err(Error::new(ErrorKind::NotFound, "gene list not found")),
+    };
+    info!("{}", format!("reading gene list from {}", filename));
+    let mut genes = HashSet::new();
+    for line in open_input(&filename)?.lines() {
+        let line = line?;
+        let line_trimmed = line.trim();
+        if line_trimmed.is_empty() || line_trimmed.starts_with('#') {
+            continue;
+        }
+        genes.insert(String::from(
+            line_trimmed.split('\t').next().unwrap_or_default(),
+        ));
+    }
+    Ok(genes)
+}
+
+impl GeneList {
+    // Whether any gene lists or patterns are given:
+    pub fn is_active(&self) -> bool {
+        self.include.is_some()
+            || !self.exclude.is_empty()
+            || !self.include_patterns.is_empty()
+            || !self.exclude_patterns.is_empty()
+    }
+
+    // Whether a gene is removed by the lists. If any allow-lists or include patterns are given, a
+    // gene must match at least one of them; a gene matching any block-list or exclude pattern is
+    // always removed:
+    pub fn is_excluded(&self, gene: &str) -> bool {
+        if self.include.is_some() || !self.include_patterns.is_empty() {
+            let included = self.include.as_ref().is_some_and(|i| i.contains(gene))
+                || self.include_patterns.iter().any(|p| p.is_match(gene));
+            if !included {
+                return true;
+            }
+        }
+        self.exclude.contains(gene) || self.exclude_patterns.iter().any(|p| p.is_match(gene))
+    }
+}
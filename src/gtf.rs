@@ -0,0 +1,174 @@
+use crate::geneids::strip_version;
+use crate::input::{expand_path, open_input};
+use log::*;
+use std::collections::HashMap;
+use std::io::prelude::*;
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+
+// The annotation columns that can be added to the output:
+pub const ANNOTATION_COLUMNS: &[&str] = &["gene_name", "gene_biotype"];
+
+// The attributes holding the gene ID, name & biotype (covering Ensembl & GENCODE GTFs and GFF3):
+const ID_ATTRIBUTES: &[&str] = &["gene_id"];
+const NAME_ATTRIBUTES: &[&str] = &["gene_name", "Name"];
+const BIOTYPE_ATTRIBUTES: &[&str] = &["gene_biotype", "gene_type", "biotype"];
+
+// The attributes holding the IDs of the features within a gene:
+const FEATURE_ATTRIBUTES: &[&str] = &["transcript_id", "exon_id"];
+
+// The annotation of a single gene:
+pub struct Gene {
+    pub chrom: String,
+    pub start: u64,
+    pub end: u64,
+    pub name: Option<String>,
+    pub biotype: Option<String>,
+}
+
+impl Gene {
+    // The genomic length of the gene:
+    pub fn length(&self) -> u64 {
+        self.end + 1 - self.start
+    }
+
+    // Format the gene's annotation columns for output:
+    pub fn columns(gene: Option<&Gene>) -> Vec<String> {
+        let na = || String::from("NA");
+        match gene {
+            Some(g) => vec![
+                g.name.clone().unwrap_or_else(na),
+                g.biotype.clone().unwrap_or_else(na),
+            ],
+            None => vec![na(), na()],
+        }
+    }
+}
+
+// Parse a GTF (key "value";) or GFF3 (key=value;) attribute column:
+fn parse_attributes(column: &str) -> HashMap<&str, &str> {
+    column
+        .split(';')
+        .filter_map(|a| a.trim().split_once([' ', '=']))
+        .map(|(k, v)| (k, v.trim().trim_matches('"')))
+        .collect()
+}
+
+// Find the first of a set of attributes present:
+fn find_attribute<'a>(attributes: &HashMap<&str, &'a str>, keys: &[&str]) -> Option<&'a str> {
+    keys.iter().find_map(|k| attributes.get(k).copied())
+}
+
+// Read the gene annotation from a (possibly gzipped) GTF or GFF3 file. Each gene spans all of the
+// records carrying its ID, and takes its chromosome, name & biotype from the first of these to
+// give them. GFF3 gene records without a gene_id attribute are identified by their ID instead.
+// If requested, version suffixes are removed from the gene IDs:
+pub fn read_gtf(path: &Path, strip_versions: bool) -> Result<HashMap<String, Gene>, Error> {
+    let filename = match expand_path(path) {
+        Some(f) => f,
+        None => return Err(Error::new(ErrorKind::NotFound, "GTF file not found")),
+    };
+    info!("{}", format!("reading gene annotation from {}", filename));
+    let mut genes: HashMap<String, Gene> = HashMap::new();
+    for (i, line) in open_input(&filename)?.lines().enumerate() {
+        let line = line?;
+        let line_trimmed = line.trim();
+        if line_trimmed.is_empty() || line_trimmed.starts_with('#') {
+            continue;
+        }
+        let line_data: Vec<_> = line_trimmed.split('\t').collect();
+        if line_data.len() != 9 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("incorrect number of columns in line {} of GTF file", i + 1),
+            ));
+        }
+        let (start, end) = match (line_data[3].parse::<u64>(), line_data[4].parse::<u64>()) {
+            (Ok(s), Ok(e)) if s <= e => (s, e),
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("invalid coordinates in line {} of GTF file", i + 1),
+                ))
+            }
+        };
+        let attributes = parse_attributes(line_data[8]);
+        let id = match find_attribute(&attributes, ID_ATTRIBUTES) {
+            Some(id) => id,
+            None if line_data[2].ends_with("gene") => match attributes.get("ID") {
+                Some(id) => id.trim_start_matches("gene:"),
+                None => continue,
+            },
+            None => continue,
+        };
+        let id = if strip_versions {
+            strip_version(id)
+        } else {
+            id
+        };
+        let gene = genes.entry(String::from(id)).or_insert_with(|| Gene {
+            chrom: String::from(line_data[0]),
+            start,
+            end,
+            name: None,
+            biotype: None,
+        });
+        gene.start = gene.start.min(start);
+        gene.end = gene.end.max(end);
+        if gene.name.is_none() {
+            gene.name = find_attribute(&attributes, NAME_ATTRIBUTES).map(String::from);
+        }
+        if gene.biotype.is_none() {
+            gene.biotype = find_attribute(&attributes, BIOTYPE_ATTRIBUTES).map(String::from);
+        }
+    }
+    info!("{}", format!("read annotation for {} genes", genes.len()));
+    Ok(genes)
+}
+
+// Read the mapping of transcript & exon IDs to gene IDs from a (possibly gzipped) GTF file.
+// If requested, version suffixes are removed from all of the IDs:
+pub fn read_feature_map(
+    path: &Path,
+    strip_versions: bool,
+) -> Result<HashMap<String, String>, Error> {
+    let filename = match expand_path(path) {
+        Some(f) => f,
+        None => return Err(Error::new(ErrorKind::NotFound, "GTF file not found")),
+    };
+    info!(
+        "{}",
+        format!("reading feature to gene mapping from {}", filename)
+    );
+    let strip = |id: &str| {
+        if strip_versions {
+            String::from(strip_version(id))
+        } else {
+            String::from(id)
+        }
+    };
+    let mut mapping = HashMap::new();
+    for line in open_input(&filename)?.lines() {
+        let line = line?;
+        let line_trimmed = line.trim();
+        if line_trimmed.is_empty() || line_trimmed.starts_with('#') {
+            continue;
+        }
+        let attributes = match line_trimmed.split('\t').nth(8) {
+            Some(a) => parse_attributes(a),
+            None => continue,
+        };
+        let gene = match find_attribute(&attributes, ID_ATTRIBUTES) {
+            Some(g) => strip(g),
+            None => continue,
+        };
+        for key in FEATURE_ATTRIBUTES {
+            if let Some(feature) = attributes.get(key) {
+                mapping
+                    .entry(strip(feature))
+                    .or_insert_with(|| gene.clone());
+            }
+        }
+    }
+    Ok(mapping)
+}
@@ -0,0 +1,22 @@
+use crate::merge::{merge_samples, SampleFile};
+use crate::reader::{CountMode, CountsReader};
+use std::io::Error;
+
+// Parse a line of htseq-count output. The count is always the final column, as
+// htseq-count places any additional attribute columns between the feature and count:
+fn parse_line(line: &str, mode: CountMode) -> Option<(String, f64)> {
+    let mut line_data = line.split('\t');
+    let gene = line_data.next()?;
+    let count = mode.parse(line_data.next_back()?)?;
+    Some((String::from(gene), count))
+}
+
+// Read a set of per-sample htseq-count outputs as a single counts matrix:
+pub fn read_htseq(files: &[SampleFile], mode: CountMode) -> Result<CountsReader, Error> {
+    Ok(CountsReader {
+        id_column: String::from("gene"),
+        annotation_columns: Vec::new(),
+        samples: files.iter().map(|f| f.name.clone()).collect(),
+        records: merge_samples(files, 0, Box::new(move |l| parse_line(l, mode)))?,
+    })
+}
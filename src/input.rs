@@ -0,0 +1,44 @@
+use flate2::read::MultiGzDecoder;
+use log::*;
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::{stdin, BufReader, Error};
+use std::path::Path;
+
+// A function to expand a Path to a full string:
+pub fn expand_path(path: &Path) -> Option<String> {
+    let path_str = path.to_str()?;
+    let path_exp = match shellexpand::full(path_str) {
+        Ok(p) => p,
+        Err(_) => return None,
+    };
+    Some(String::from(path_exp))
+}
+
+// The two magic bytes that start every gzip (and bgzip) member:
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+// Wrap a buffered reader, decompressing it if it starts with the gzip magic bytes.
+// A MultiGzDecoder is used so that multi-member files (such as bgzip) are read in full:
+fn decompress_if_gzipped<R: BufRead + 'static>(
+    mut reader: R,
+    name: &str,
+) -> Result<Box<dyn BufRead>, Error> {
+    if reader.fill_buf()?.starts_with(&GZIP_MAGIC) {
+        debug!("{}", format!("decompressing gzip input {}", name));
+        Ok(Box::new(BufReader::new(MultiGzDecoder::new(reader))))
+    } else {
+        Ok(Box::new(reader))
+    }
+}
+
+// Open an input file for buffered reading, transparently decompressing gzip or bgzip data:
+pub fn open_input(filename: &str) -> Result<Box<dyn BufRead>, Error> {
+    let file = File::open(filename)?;
+    decompress_if_gzipped(BufReader::new(file), filename)
+}
+
+// Open stdin for buffered reading, transparently decompressing gzip or bgzip data:
+pub fn open_stdin() -> Result<Box<dyn BufRead>, Error> {
+    decompress_if_gzipped(BufReader::new(stdin()), "stdin")
+}
@@ -1,43 +1,128 @@
+use collapse::Collapse;
+use filter::{
+    format_reasons, parse_proportion, FilterByExpr, FilterReason, GeneFilter, GeneStats,
+    SampleCount, ValueScale,
+};
+use geneids::DuplicatePolicy;
+use genelist::GeneList;
+use input::expand_path;
 use log::*;
-use shellexpand;
-use signal_hook;
-use std::fs::File;
+use normalise::Normalisation;
+use output::Output;
+use reader::{CountMode, InputFormat, Record};
+use regex::Regex;
+use sampleqc::SampleQc;
+use samplesheet::SampleSheet;
+use star::StarStrand;
 use std::io::prelude::*;
-use std::io::{stdout, Error, ErrorKind};
-use std::io::{BufReader, BufWriter};
-use std::path::PathBuf;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
 use std::process;
 use structopt::StructOpt;
+use transform::{Transform, Vst};
+use variable::Variability;
+
+mod aggregate;
+mod collapse;
+mod featurecounts;
+mod filter;
+mod geneids;
+mod genelist;
+mod gtf;
+mod htseq;
+mod input;
+mod merge;
+mod mtx;
+mod normalise;
+mod output;
+mod quant;
+mod reader;
+mod sampleqc;
+mod sampleselect;
+mod samplesheet;
+mod star;
+mod stats;
+mod transform;
+mod variable;
+
+// The featureCounts metacount duplicating the gene counts:
+const ASSIGNED_METACOUNT: &str = "__Assigned";
 
 // Define a struct to hold sample metadata:
 #[derive(Debug)]
 struct Sample {
     name: String,
-    metacounts: Vec<u64>,
-    total_count: u64,
-    passed_count: u64,
+    metacounts: Vec<f64>,
+    total_count: f64,
+    passed_count: f64,
     total_expressed: u64,
     passed_expressed: u64,
+    group_passed: u64,
+    excluded_count: f64,
 }
 
-// Define a struct to record how we're outputting metacounts:
+impl Sample {
+    // A sample with no counts accumulated:
+    fn new(name: String) -> Sample {
+        Sample {
+            name,
+            metacounts: Vec::with_capacity(5),
+            total_count: 0.0,
+            passed_count: 0.0,
+            total_expressed: 0,
+            passed_expressed: 0,
+            group_passed: 0,
+            excluded_count: 0.0,
+        }
+    }
+
+    // The sample's total reads: the gene counts plus all metacounts (other than featureCounts'
+    // Assigned, which duplicates the gene counts):
+    fn total_reads(&self, metacount_names: &[String]) -> f64 {
+        self.total_count
+            + self
+                .metacounts
+                .iter()
+                .zip(metacount_names.iter())
+                .filter(|(_, m)| *m != ASSIGNED_METACOUNT)
+                .map(|(v, _)| v)
+                .sum::<f64>()
+    }
+}
+
+// Define a struct to hold the results of filtering a counts matrix:
+struct FilterResult {
+    samples: Vec<Sample>,
+    metacount_names: Vec<String>,
+    total_genes: u64,
+    passed_genes: u64,
+    excluded_genes: u64,
+    n_annotation_columns: usize,
+}
+
+// Define a struct to record how we're outputting metacounts. Metacounts written to stdout are
+// padded with NA annotation fields, so that they line up with the counts matrix:
 struct MetacountDestination {
-    handle: Box<dyn Write>,
+    handle: Output,
     is_stdout: bool,
     prefix: String,
+    padding: String,
 }
 
-// A function to expand a PathBuf to a full string:
-fn expand_path(path: &PathBuf) -> Option<String> {
-    let path_str = match path.to_str() {
-        Some(p) => p,
-        None => return None,
-    };
-    let path_exp = match shellexpand::full(path_str) {
-        Ok(p) => p,
-        Err(_) => return None,
+// A function to create an output file from a commandline path:
+fn create_output(path: &Path, description: &str) -> Result<Output, Error> {
+    let filename = match expand_path(path) {
+        Some(f) => f,
+        None => {
+            return Err(Error::new(
+                ErrorKind::NotFound,
+                format!("{} file not found", description),
+            ))
+        }
     };
-    Some(String::from(path_exp))
+    let f = Output::create(&filename)?;
+    info!("{}", format!("writing {} to {}", description, filename));
+    Ok(f)
 }
 
 // A function to write a named metacount set to outp;ut:
@@ -45,16 +130,17 @@ fn write_metacount(
     dest: &mut MetacountDestination,
     samples: &[Sample],
     name: &str,
-    f: impl Fn(&Sample) -> u64,
+    f: impl Fn(&Sample) -> f64,
 ) -> Result<(), Error> {
     writeln!(
         dest.handle,
-        "{}{}\t{}",
+        "{}{}{}\t{}",
         dest.prefix,
         name,
+        dest.padding,
         samples
             .iter()
-            .map(|s| f(s).to_string())
+            .map(|s| reader::format_value(f(s)))
             .collect::<Vec<String>>()
             .join("\t")
     )
@@ -63,10 +149,24 @@ fn write_metacount(
 // Define the CLI:
 #[derive(StructOpt)]
 #[structopt(about = "Filter HTSeq counts matrix files")]
-#[structopt(after_help = "The following filters are applied to each gene:
+#[structopt(
+    after_help = "The following filters are applied to each gene, in this order (a gene \
+failing any of them is removed, and --removed lists every filter it failed):
+* The gene is filtered on the gene lists (if --include-genes, --exclude-genes, --include-pattern \
+or --exclude-pattern are specified);
+* The gene is filtered on its annotation (if --keep-biotype, --exclude-chrom or --min-gene-length \
+are specified);
 * The gene is filtered on total read count (if -m is specified);
-* The gene is filtered on zero count (if -z is specified);
-* The gene is filtered on non-zero variance (if -i is specified)")]
+* The gene is filtered on the number of expressed samples (if -e is specified);
+* The gene is filtered on counts per million (if --min-cpm is specified);
+* The gene is filtered as edgeR's filterByExpr (if --filter-by-expr is specified);
+* The gene is filtered on expressed samples within groups (if --min-group-expressed or \
+--min-group-prop are specified);
+* The gene is filtered on variance, coefficient of variation & log variance (if --min-variance, \
+--min-cv or --min-log-variance are specified);
+* The gene is filtered on non-zero variance (if -i is specified).
+Any --top-variable selection is then made from the genes passing all of these filters."
+)]
 struct Cli {
     #[structopt(
         short = "v",
@@ -76,17 +176,139 @@ struct Cli {
     )]
     verbose: usize,
     #[structopt(short="m", long="min-count", value_names=&["n"], help="Minimum total gene count")]
-    min_count: Option<u64>,
-    #[structopt(short="e", long="min-expressed", value_names=&["n"], help="Minimum number of expressed samples")]
-    min_expressed: Option<u64>,
+    min_count: Option<f64>,
+    #[structopt(short="e", long="min-expressed", value_names=&["n"], help="Minimum number of expressed samples (or proportion of samples, e.g. 0.25 or 25%)")]
+    min_expressed: Option<SampleCount>,
+    #[structopt(long="min-cpm", value_names=&["x"], help="Minimum counts per million (CPM) in at least --min-cpm-samples samples")]
+    min_cpm: Option<f64>,
+    #[structopt(long="min-cpm-samples", value_names=&["n"], default_value="1", help="Minimum number (or proportion) of samples with CPM of at least --min-cpm")]
+    min_cpm_samples: SampleCount,
+    #[structopt(
+        long = "lib-size-metacounts",
+        help = "Include metacounts in the library sizes used to calculate CPM"
+    )]
+    lib_size_metacounts: bool,
+    #[structopt(
+        long = "filter-by-expr",
+        help = "Filter genes as edgeR's filterByExpr (using the groups from --sample-sheet, if given)"
+    )]
+    filter_by_expr: bool,
+    #[structopt(long="fbe-min-count", value_names=&["n"], default_value="10", help="filterByExpr min.count: the minimum count required in at least some samples")]
+    fbe_min_count: f64,
+    #[structopt(long="fbe-min-total-count", value_names=&["n"], default_value="15", help="filterByExpr min.total.count: the minimum total count")]
+    fbe_min_total_count: f64,
+    #[structopt(long="fbe-large-n", value_names=&["n"], default_value="10", help="filterByExpr large.n: the number of samples per group considered large")]
+    fbe_large_n: f64,
+    #[structopt(long="fbe-min-prop", value_names=&["p"], default_value="0.7", help="filterByExpr min.prop: the minimum proportion of samples in a large group")]
+    fbe_min_prop: f64,
+    #[structopt(parse(from_os_str), long="sample-sheet", value_names=&["path"], help="Read sample groups from a tab-separated sample sheet (sample name, group)")]
+    sample_sheet: Option<PathBuf>,
+    #[structopt(long="min-group-expressed", value_names=&["n"], help="Minimum number (or proportion) of expressed samples in at least one group (requires --sample-sheet)")]
+    min_group_expressed: Option<SampleCount>,
+    #[structopt(long="min-group-prop", value_names=&["p"], parse(try_from_str=parse_proportion), help="Minimum proportion (e.g. 0.5 or 50%) of expressed samples in every group (requires --sample-sheet)")]
+    min_group_prop: Option<f64>,
     #[structopt(
         short = "i",
         long = "filter-identical",
         help = "Filter out genes with zero variance (i.e. with all values identical)"
     )]
     filter_identical: bool,
+    #[structopt(long="min-variance", value_names=&["x"], help="Minimum gene variance (on the --variance-scale)")]
+    min_variance: Option<f64>,
+    #[structopt(long="min-cv", value_names=&["x"], help="Minimum gene coefficient of variation (on the --variance-scale)")]
+    min_cv: Option<f64>,
+    #[structopt(long="min-log-variance", value_names=&["x"], help="Minimum variance of log2(value + --pseudocount) (with values on the --variance-scale)")]
+    min_log_variance: Option<f64>,
+    #[structopt(long="variance-scale", value_names=&["scale"], default_value="raw", possible_values=ValueScale::NAMES, help="Scale on which the variance filters are calculated (raw counts or CPM)")]
+    variance_scale: ValueScale,
+    #[structopt(long="collapse", value_names=&["path"], parse(from_os_str), conflicts_with="collapse-regex", help="Sum columns into merged samples, using a tab-separated file mapping columns to samples")]
+    collapse_path: Option<PathBuf>,
+    #[structopt(long="collapse-regex", value_names=&["regex"], help="Sum columns into merged samples named by the regex's first capture group (or by removing its match)")]
+    collapse_regex: Option<Regex>,
+    #[structopt(long="aggregate", value_names=&["path"], parse(from_os_str), help="Sum the rows of features mapped to the same gene, using a tab-separated file mapping features to genes")]
+    aggregate_path: Option<PathBuf>,
+    #[structopt(
+        long = "aggregate-gtf",
+        conflicts_with = "aggregate-path",
+        help = "Sum the rows of transcripts or exons of the same gene, using the mapping from --gtf"
+    )]
+    aggregate_gtf: bool,
+    #[structopt(
+        long = "drop-unmapped",
+        help = "Remove features not mapped to a gene when aggregating (rather than keeping them unchanged)"
+    )]
+    drop_unmapped: bool,
+    #[structopt(long="samples", value_names=&["samples"], help="Keep only the given samples, in the given order (as a file with one name per line, or a comma-separated list)")]
+    samples: Option<String>,
+    #[structopt(long="drop-samples", value_names=&["samples"], help="Remove the given samples (as a file with one name per line, or a comma-separated list)")]
+    drop_samples: Option<String>,
+    #[structopt(long="rename", value_names=&["path"], parse(from_os_str), help="Tab-separated file mapping old sample names to new names")]
+    rename_path: Option<PathBuf>,
+    #[structopt(
+        long = "strip-versions",
+        help = "Remove version suffixes (such as the .17 of ENSG00000141510.17) from gene IDs"
+    )]
+    strip_versions: bool,
+    #[structopt(long="duplicates", value_names=&["policy"], default_value="keep", possible_values=DuplicatePolicy::NAMES, help="How rows with duplicate gene IDs are handled")]
+    duplicates: DuplicatePolicy,
+    #[structopt(long="include-genes", value_names=&["file"], parse(from_os_str), help="Keep only the genes listed in the given file (one ID per line)")]
+    include_genes: Option<PathBuf>,
+    #[structopt(long="exclude-genes", value_names=&["file"], parse(from_os_str), help="Remove the genes listed in the given file (one ID per line)")]
+    exclude_genes: Option<PathBuf>,
+    #[structopt(long="include-pattern", value_names=&["regex"], number_of_values=1, help="Keep only the genes whose IDs match the given regular expression (may be repeated)")]
+    include_patterns: Vec<Regex>,
+    #[structopt(long="exclude-pattern", value_names=&["regex"], number_of_values=1, help="Remove the genes whose IDs match the given regular expression (may be repeated)")]
+    exclude_patterns: Vec<Regex>,
+    #[structopt(long="min-library-size", value_names=&["n"], help="Remove samples with a library size below n")]
+    min_library_size: Option<f64>,
+    #[structopt(long="min-detected-genes", value_names=&["n"], help="Remove samples with fewer than n expressed genes (at the -x threshold)")]
+    min_detected_genes: Option<u64>,
+    #[structopt(long="max-no-feature-fraction", value_names=&["p"], parse(try_from_str=parse_proportion), help="Remove samples with more than this fraction of reads assigned to no feature")]
+    max_no_feature_fraction: Option<f64>,
+    #[structopt(long="max-ambiguous-fraction", value_names=&["p"], parse(try_from_str=parse_proportion), help="Remove samples with more than this fraction of ambiguously assigned reads")]
+    max_ambiguous_fraction: Option<f64>,
+    #[structopt(long="removed-samples", value_names=&["path"], parse(from_os_str), help="File to write the samples removed by QC (with the reasons) to")]
+    removed_samples_path: Option<PathBuf>,
+    #[structopt(long="gtf", value_names=&["path"], parse(from_os_str), help="GTF or GFF3 gene annotation (used by the annotation filters & --annotate)")]
+    gtf_path: Option<PathBuf>,
+    #[structopt(long="keep-biotype", value_names=&["biotypes"], use_delimiter=true, require_delimiter=true, help="Keep only genes of the given comma-separated biotypes (requires --gtf)")]
+    keep_biotypes: Vec<String>,
+    #[structopt(long="exclude-chrom", value_names=&["chroms"], use_delimiter=true, require_delimiter=true, help="Remove genes on the given comma-separated chromosomes (requires --gtf)")]
+    exclude_chroms: Vec<String>,
+    #[structopt(long="min-gene-length", value_names=&["n"], help="Minimum gene length, from the gene's annotated span (requires --gtf)")]
+    min_gene_length: Option<u64>,
+    #[structopt(
+        long = "annotate",
+        help = "Add gene name & biotype columns to the output (requires --gtf)"
+    )]
+    annotate: bool,
+    #[structopt(long="normalise", value_names=&["method"], possible_values=Normalisation::NAMES, help="Write normalised values for the passing genes (CPM, TMM, upper-quartile or DESeq2 median-of-ratios)")]
+    normalise: Option<Normalisation>,
+    #[structopt(long="size-factors", value_names=&["path"], parse(from_os_str), help="File to write the per-sample normalisation size factors to (requires --normalise)")]
+    size_factors_path: Option<PathBuf>,
+    #[structopt(long="transform", value_names=&["transform"], possible_values=Transform::NAMES, help="Transform the values of the passing genes (log2 uses --pseudocount, as does log2cpm as a prior count)")]
+    transform: Option<Transform>,
+    #[structopt(long="decimals", value_names=&["n"], help="Number of decimal places written for the output values")]
+    decimals: Option<usize>,
+    #[structopt(long="top-variable", value_names=&["n"], help="Keep only the n most variable genes passing the other filters")]
+    top_variable: Option<usize>,
+    #[structopt(long="top-variable-by", value_names=&["measure"], default_value="variance", possible_values=Variability::NAMES, help="Measure used to rank genes for --top-variable")]
+    top_variable_by: Variability,
+    #[structopt(long="pseudocount", value_names=&["x"], default_value="1", help="Pseudocount added before log transformation")]
+    pseudocount: f64,
     #[structopt(short="x", long="expression", value_names=&["e"], default_value="1", help="Minimum expression count")]
-    expression_threshold: u64,
+    expression_threshold: f64,
+    #[structopt(
+        long = "fractional",
+        help = "Allow fractional (estimated) counts. Implied by the salmon, kallisto & rsem formats"
+    )]
+    fractional: bool,
+    #[structopt(long = "round", help = "Round counts to the nearest integer")]
+    round: bool,
+    #[structopt(parse(from_os_str), long="removed", value_names=&["path"], help="Write the removed genes (with a column listing the filters each failed) to file")]
+    removed_path: Option<PathBuf>,
+    #[structopt(parse(from_os_str), long="gene-stats", value_names=&["path"], help="Write per-gene statistics (for all genes, including those removed) to file")]
+    gene_stats_path: Option<PathBuf>,
     #[structopt(parse(from_os_str), short="o", long="metacount-file", value_names=&["path"], help="Extract metacounts (starting with double underscores) to file")]
     metacount_path: Option<PathBuf>,
     #[structopt(
@@ -95,235 +317,901 @@ struct Cli {
         help = "Include sample summary metacounts"
     )]
     summary_metacounts: bool,
-    #[structopt(parse(from_os_str), help = "Input counts file")]
-    path: PathBuf,
+    #[structopt(parse(from_os_str), long="output", value_names=&["path"], help="Write the filtered counts to file rather than stdout (gzip compressed if ending in .gz). For Matrix Market input, the output directory")]
+    output_path: Option<PathBuf>,
+    #[structopt(short="f", long="format", value_names=&["format"], default_value="matrix", possible_values=InputFormat::NAMES, help="Input format (matrix: a counts matrix with a header row; htseq: per-sample htseq-count outputs to merge; featurecounts: a featureCounts table; star: per-sample STAR ReadsPerGene.out.tab files to merge; salmon, kallisto & rsem: per-sample quant.sf, abundance.tsv or .results files to merge; mtx: a Matrix Market directory)")]
+    format: InputFormat,
+    #[structopt(parse(from_os_str), long="featurecounts-summary", value_names=&["path"], help="Read featureCounts summary metacounts from file (defaults to the input path with .summary appended, if present)")]
+    featurecounts_summary: Option<PathBuf>,
+    #[structopt(long="star-strand", value_names=&["strand"], default_value="auto", possible_values=StarStrand::NAMES, help="STAR ReadsPerGene count column to use (auto infers the strandedness from the noFeature counts)")]
+    star_strand: StarStrand,
+    #[structopt(parse(from_os_str), long="file-list", value_names=&["path"], help="Read per-sample input files from file (one per line, optionally followed by a tab and the sample name)")]
+    file_list: Option<PathBuf>,
+    #[structopt(
+        parse(from_os_str),
+        help = "Input counts file (optionally gzip or bgzip compressed). Use - or omit to read from stdin. Per-sample formats accept multiple files or glob patterns"
+    )]
+    paths: Vec<PathBuf>,
 }
 
-fn main() -> Result<(), Error> {
-    // Capture the commandline arguments:
-    let args = Cli::from_args();
-
-    // Register the SIGPIPE actions:
-    let _signal = unsafe {
-        signal_hook::register(signal_hook::SIGPIPE, || {
-            process::exit(128 + signal_hook::SIGPIPE)
-        })
+// Read, filter & write a dense counts matrix:
+fn filter_matrix(
+    args: &Cli,
+    count_mode: CountMode,
+    mut gene_filter: GeneFilter,
+) -> Result<FilterResult, Error> {
+    // Open the input (reading from stdin if no path or "-" is given):
+    let mut counts_reader = if args.format.is_per_sample() {
+        let files = merge::sample_files(&args.paths, args.file_list.as_deref())?;
+        info!(
+            "{}",
+            format!("merging counts from {} sample files", files.len())
+        );
+        match args.format {
+            InputFormat::Htseq => htseq::read_htseq(&files, count_mode)?,
+            InputFormat::Star => star::read_star(&files, args.star_strand, count_mode)?,
+            f if f.is_estimated() => quant::read_quant(&files, f, count_mode)?,
+            _ => unreachable!(),
+        }
+    } else {
+        if args.paths.len() > 1 || args.file_list.is_some() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "multiple inputs require a per-sample input format",
+            ));
+        }
+        let input_filename = match args.paths.first() {
+            Some(p) if p != Path::new("-") => match expand_path(p) {
+                Some(f) => Some(f),
+                None => return Err(Error::new(ErrorKind::NotFound, "input file not found")),
+            },
+            _ => None,
+        };
+        let input_buffer = match input_filename {
+            Some(ref f) => {
+                let input_buffer = input::open_input(f)?;
+                info!("{}", format!("reading counts from {}", f));
+                input_buffer
+            }
+            None => {
+                info!("reading counts from stdin");
+                input::open_stdin()?
+            }
+        };
+        match args.format {
+            InputFormat::FeatureCounts => {
+                let summary_filename = match args.featurecounts_summary {
+                    Some(ref p) => match expand_path(p) {
+                        Some(f) => Some(f),
+                        None => {
+                            return Err(Error::new(
+                                ErrorKind::NotFound,
+                                "featureCounts summary file not found",
+                            ))
+                        }
+                    },
+                    None => input_filename
+                        .as_deref()
+                        .and_then(featurecounts::find_summary),
+                };
+                let summary = match summary_filename {
+                    Some(ref f) => {
+                        info!("{}", format!("reading featureCounts summary from {}", f));
+                        Some(input::open_input(f)?)
+                    }
+                    None => None,
+                };
+                featurecounts::read_featurecounts(input_buffer, summary, count_mode)?
+            }
+            _ => reader::read_matrix(input_buffer, count_mode)?,
+        }
     };
 
-    // Build the log:
-    if stderrlog::new()
-        .module(module_path!())
-        .verbosity(args.verbose)
-        .init()
-        .is_err()
-    {
-        return Err(Error::new(ErrorKind::Other, "failed to initialise logger"));
-    }
+    // Sort out the filtered counts destination:
+    let mut output = match args.output_path {
+        Some(ref p) => create_output(p, "filtered counts")?,
+        None => Output::stdout(),
+    };
 
-    // Attempt to open the input file:
-    let input_filename = match expand_path(&args.path) {
-        Some(f) => f,
-        None => return Err(Error::new(ErrorKind::NotFound, "input file not found")),
+    // Sort out the gene statistics destination:
+    let mut gene_stats_output = match args.gene_stats_path {
+        Some(ref p) => Some(create_output(p, "gene statistics")?),
+        None => None,
     };
-    let input_file = File::open(&input_filename)?;
-    let input_buffer = BufReader::new(input_file);
-    info!("{}", format!("reading counts from {}", input_filename));
 
-    // Sort out the metacount destination:
-    let mut metacount_dest = match args.metacount_path {
-        Some(p) => {
-            let metacount_filename = match expand_path(&p) {
-                Some(f) => f,
-                None => {
-                    return Err(Error::new(
-                        ErrorKind::NotFound,
-                        "metacount output file not found",
-                    ))
-                }
-            };
-            let f = File::create(&metacount_filename)?;
-            info!(
-                "{}",
-                format!("writing metacounts to {}", metacount_filename)
-            );
-            MetacountDestination {
-                handle: Box::new(BufWriter::new(f)),
-                is_stdout: false,
-                prefix: String::from(""),
-            }
-        }
-        None => {
-            info!("writing metacounts to stdout");
-            MetacountDestination {
-                handle: Box::new(stdout()),
-                is_stdout: true,
-                prefix: String::from("__"),
-            }
-        }
+    // Sort out the removed genes destination:
+    let mut removed = match args.removed_path {
+        Some(ref p) => Some(create_output(p, "removed genes")?),
+        None => None,
     };
 
     // Assign a Vec to capture the metacount names:
     let mut metacount_names: Vec<String> = Vec::with_capacity(5);
 
-    // Get the line iterator:
-    let mut line_iter = input_buffer.lines();
-
-    // Read the file header:
-    let file_header = match line_iter.next() {
-        Some(Ok(h)) => Ok(h),
-        _ => Err(Error::new(
-            ErrorKind::UnexpectedEof,
-            "failed to read input file header",
-        )),
-    }?;
+    // Add any gene annotation columns:
+    if args.annotate {
+        counts_reader
+            .annotation_columns
+            .extend(gtf::ANNOTATION_COLUMNS.iter().map(|c| String::from(*c)));
+    }
 
-    // Write out the file header:
-    println!("{}", file_header);
+    // Sort out the removed samples destination:
+    let mut removed_samples = match args.removed_samples_path {
+        Some(ref p) => Some(create_output(p, "removed samples")?),
+        None => None,
+    };
 
     // Initialise the sample metadata structs:
-    let mut samples: Vec<Sample> = file_header
-        .trim()
-        .split('\t')
-        .skip(1)
-        .map(|sample| Sample {
-            name: String::from(sample),
-            metacounts: Vec::with_capacity(5),
-            total_count: 0,
-            passed_count: 0,
-            total_expressed: 0,
-            passed_expressed: 0,
-        })
+    let records = std::mem::replace(&mut counts_reader.records, Box::new(std::iter::empty()));
+    let mut samples: Vec<Sample> = counts_reader
+        .samples
+        .iter()
+        .map(|name| Sample::new(name.clone()))
         .collect();
 
-    // Record the total & filtered genes:
-    let mut total_genes: u64 = 0;
-    let mut passed_genes: u64 = 0;
-
-    // Iterate over the remaining lines:
-    for line_res in line_iter {
-        let line = line_res
-            .map_err(|_| Error::new(ErrorKind::InvalidData, "failed to parse input file"))?;
-        let line_trimmed = line.trim();
-        let line_data: Vec<_> = line_trimmed.split('\t').collect();
-        let gene = &line_data[0];
-
-        // Extract the counts:
-        let counts: Vec<_> = match line_data.iter().skip(1).map(|s| s.parse::<u64>()).collect() {
-            Ok(c) => c,
-            Err(_) => {
-                warn!(
-                    "{}",
-                    format!("failed to convert counts from line {}", line.trim())
-                );
-                continue;
-            }
-        };
+    // Read the matrix rows, separating the metagenes:
+    let mut genes: Vec<Record> = Vec::new();
+    for record in records {
+        let mut record = record?;
 
         // Check if this is a metagene:
-        if gene.starts_with("__") {
-            metacount_names.push(String::from(*gene));
-            for (i, v) in counts.iter().enumerate() {
+        if record.gene.starts_with("__") {
+            for (i, v) in record.counts.iter().enumerate() {
                 samples[i].metacounts.push(*v);
             }
+            metacount_names.push(record.gene);
             continue;
         }
 
-        total_genes += 1;
+        if args.strip_versions {
+            let stripped = geneids::strip_version(&record.gene).len();
+            record.gene.truncate(stripped);
+        }
+        genes.push(record);
+    }
 
-        // Calculate the gene stats:
-        let mut gene_total: u64 = 0;
-        let mut gene_nexpressed: u64 = 0;
-        let mut gene_filtered = false;
-        for (i, v) in counts.iter().enumerate() {
-            gene_total += v;
+    // Sum the columns of each merged sample:
+    let collapse = match (args.collapse_path.as_ref(), args.collapse_regex.as_ref()) {
+        (Some(p), _) => Some(Collapse::read(p)?),
+        (None, Some(r)) => Some(Collapse::Pattern(r.clone())),
+        (None, None) => None,
+    };
+    if let Some(collapse) = collapse {
+        let (merged_names, indices) = collapse.groups(&counts_reader.samples);
+        info!(
+            "{}",
+            format!(
+                "collapsed {} columns into {} samples",
+                indices.len(),
+                merged_names.len()
+            )
+        );
+        let mut merged: Vec<Sample> = merged_names.iter().cloned().map(Sample::new).collect();
+        for m in merged.iter_mut() {
+            m.metacounts = vec![0.0; metacount_names.len()];
+        }
+        for (s, i) in samples.iter().zip(indices.iter()) {
+            for (m, v) in merged[*i].metacounts.iter_mut().zip(s.metacounts.iter()) {
+                *m += v;
+            }
+        }
+        for record in genes.iter_mut() {
+            record.counts = collapse::sum_columns(&record.counts, &indices, merged.len());
+        }
+        samples = merged;
+        counts_reader.samples = merged_names;
+    }
+
+    // Select, reorder & rename the samples:
+    if args.samples.is_some() || args.drop_samples.is_some() {
+        let selection = match args.samples {
+            Some(ref s) => Some(sampleselect::parse_sample_list(s)?),
+            None => None,
+        };
+        let dropped = match args.drop_samples {
+            Some(ref s) => sampleselect::parse_sample_list(s)?,
+            None => Vec::new(),
+        };
+        let indices =
+            sampleselect::select_samples(&counts_reader.samples, selection.as_deref(), &dropped)?;
+        info!(
+            "{}",
+            format!(
+                "selected {} / {} samples",
+                indices.len(),
+                counts_reader.samples.len()
+            )
+        );
+        samples = sampleselect::reorder(samples, &indices);
+        counts_reader.samples = sampleselect::reorder(counts_reader.samples, &indices);
+        for record in genes.iter_mut() {
+            record.counts = indices.iter().map(|i| record.counts[*i]).collect();
+        }
+    }
+    if let Some(ref p) = args.rename_path {
+        sampleselect::rename_samples(&mut counts_reader.samples, &sampleselect::read_rename(p)?)?;
+        for (s, name) in samples.iter_mut().zip(counts_reader.samples.iter()) {
+            s.name = name.clone();
+        }
+    }
+
+    // Aggregate the features to genes:
+    let mapping = match (args.aggregate_path.as_ref(), args.gtf_path.as_ref()) {
+        (Some(p), _) => Some(aggregate::read_mapping(p, args.strip_versions)?),
+        (None, Some(p)) if args.aggregate_gtf => {
+            Some(gtf::read_feature_map(p, args.strip_versions)?)
+        }
+        _ => None,
+    };
+    if let Some(mapping) = mapping {
+        let (aggregated, summary) = aggregate::aggregate(genes, &mapping, !args.drop_unmapped);
+        genes = aggregated;
+        info!(
+            "{}",
+            format!(
+                "aggregated {} features into {} genes",
+                summary.n_mapped, summary.n_genes
+            )
+        );
+        if summary.n_unmapped > 0 {
+            warn!(
+                "{}",
+                format!(
+                    "{} features are not mapped to a gene ({})",
+                    summary.n_unmapped,
+                    if args.drop_unmapped {
+                        "dropped"
+                    } else {
+                        "kept"
+                    }
+                )
+            );
+        }
+    }
+
+    // Collapse any duplicate gene IDs:
+    let (mut genes, n_duplicated) = geneids::collapse_duplicates(genes, args.duplicates)?;
+    if n_duplicated > 0 {
+        if args.duplicates == DuplicatePolicy::Keep {
+            warn!("{}", format!("{} gene IDs are duplicated", n_duplicated));
+        } else {
+            info!(
+                "{}",
+                format!("collapsed {} duplicated gene IDs", n_duplicated)
+            );
+        }
+    }
+
+    // Accumulate the sample totals:
+    for record in genes.iter() {
+        for (i, v) in record.counts.iter().enumerate() {
             samples[i].total_count += v;
             if v >= &args.expression_threshold {
-                gene_nexpressed += 1;
                 samples[i].total_expressed += 1;
             }
         }
+    }
 
-        // Filter by minimum count:
-        match args.min_count {
-            Some(min_count) if gene_total < min_count => {
-                debug!(
-                    "{}",
-                    format!(
-                        "gene {} failed filtering (total count {} < {})",
-                        gene, gene_total, min_count
-                    )
-                );
-                gene_filtered = true;
+    // Calculate a sample's library size (used for CPM filtering & sample QC):
+    let lib_size = |s: &Sample| {
+        if args.lib_size_metacounts {
+            s.total_reads(&metacount_names)
+        } else {
+            s.total_count
+        }
+    };
+
+    // Apply the sample QC filters, removing the columns of any failing samples:
+    let sample_qc = SampleQc {
+        min_library_size: args.min_library_size,
+        min_detected_genes: args.min_detected_genes,
+        max_no_feature_fraction: args.max_no_feature_fraction,
+        max_ambiguous_fraction: args.max_ambiguous_fraction,
+    };
+    if let Some(ref mut r) = removed_samples {
+        writeln!(r, "sample\treason")?;
+    }
+    if sample_qc.is_active() {
+        sample_qc.check_metacounts(&metacount_names);
+        let mut keep = Vec::with_capacity(samples.len());
+        for s in samples.iter() {
+            let reasons = sample_qc.test(s, lib_size(s), &metacount_names);
+            if !reasons.is_empty() {
+                let reasons = sampleqc::format_reasons(&reasons);
+                warn!("{}", format!("sample {} failed QC ({})", s.name, reasons));
+                if let Some(ref mut r) = removed_samples {
+                    writeln!(r, "{}\t{}", s.name, reasons)?;
+                }
             }
-            _ => (),
+            keep.push(reasons.is_empty());
+        }
+        let n_samples = samples.len();
+        sampleqc::retain_samples(&mut samples, &keep);
+        sampleqc::retain_samples(&mut counts_reader.samples, &keep);
+        for record in genes.iter_mut() {
+            sampleqc::retain_samples(&mut record.counts, &keep);
+        }
+        info!(
+            "{}",
+            format!("{} / {} samples passed QC", samples.len(), n_samples)
+        );
+        if samples.is_empty() {
+            return Err(Error::new(ErrorKind::InvalidData, "no samples passed QC"));
+        }
+    }
+    if let Some(r) = removed_samples {
+        r.finish()?;
+    }
+
+    // Write out the file headers (other than the main output's, which waits until any size
+    // factors have been calculated, so that a failure leaves it empty):
+    if let Some(ref mut r) = removed {
+        writeln!(r, "{}\treason", counts_reader.header())?;
+    }
+    if let Some(ref mut g) = gene_stats_output {
+        let mut header = vec![counts_reader.id_column.as_str()];
+        header.extend(stats::GENE_STATS_COLUMNS);
+        writeln!(g, "{}", header.join("\t"))?;
+    }
+
+    // Check the genes against the annotation, adding the annotation columns if requested:
+    if let Some(ref annotation) = gene_filter.annotation {
+        let mut n_missing = 0;
+        for record in genes.iter_mut() {
+            let gene = annotation.get(&record.gene);
+            if gene.is_none() {
+                n_missing += 1;
+            }
+            if args.annotate {
+                record.annotation.extend(gtf::Gene::columns(gene));
+            }
+        }
+        if n_missing > 0 {
+            warn!(
+                "{}",
+                format!("{} genes are not in the GTF annotation", n_missing)
+            );
+        }
+    }
+
+    // Look up the sample groups (treating all samples as a single group if there is no sample sheet):
+    let sample_names: Vec<String> = samples.iter().map(|s| s.name.clone()).collect();
+    let groups = match args.sample_sheet {
+        Some(ref p) => SampleSheet::read(p)?.groups(&sample_names)?,
+        None => vec![String::new(); samples.len()],
+    };
+    let (group_names, group_indices) = samplesheet::index_groups(&groups);
+    if args.sample_sheet.is_some() {
+        info!(
+            "{}",
+            format!(
+                "{} sample groups: {}",
+                group_names.len(),
+                group_names.join(", ")
+            )
+        );
+    }
+
+    // Calculate the library sizes (used for CPM filtering):
+    let lib_sizes: Vec<f64> = samples.iter().map(lib_size).collect();
+    gene_filter.prepare(lib_sizes.clone(), group_indices.clone());
+
+    // Record the total & filtered genes (and the genes meeting the group filters in each group):
+    let total_genes = genes.len() as u64;
+    let mut passed_genes: u64 = 0;
+    let mut excluded_genes: u64 = 0;
+    let mut group_passed = vec![0u64; group_names.len()];
+
+    // Filter the genes:
+    let mut gene_reasons: Vec<Vec<FilterReason>> = Vec::with_capacity(genes.len());
+    let mut gene_expressed: Vec<u64> = Vec::with_capacity(genes.len());
+    for record in genes.iter() {
+        let counts = &record.counts;
+
+        // Calculate the gene stats:
+        let gene_stats = GeneStats {
+            n_samples: counts.len(),
+            total: counts.iter().sum(),
+            n_expressed: counts
+                .iter()
+                .filter(|v| **v >= args.expression_threshold)
+                .count() as u64,
+            identical: counts.iter().all(|i| *i == counts[0]),
         };
 
-        // Filter on zero count:
-        match args.min_expressed {
-            Some(min_expressed) if gene_nexpressed < min_expressed => {
-                debug!(
+        gene_reasons.push(gene_filter.test(&record.gene, counts, &gene_stats));
+        gene_expressed.push(gene_stats.n_expressed);
+        if gene_filter.has_group_filters() {
+            for (n, passed) in group_passed
+                .iter_mut()
+                .zip(gene_filter.group_passes(counts))
+            {
+                if passed {
+                    *n += 1;
+                }
+            }
+        }
+    }
+
+    // Keep only the most variable of the genes passing the other filters:
+    if let Some(n) = args.top_variable {
+        let passing: Vec<usize> = (0..genes.len())
+            .filter(|i| gene_reasons[*i].is_empty())
+            .collect();
+        let passing_counts: Vec<&[f64]> = passing
+            .iter()
+            .map(|i| genes[*i].counts.as_slice())
+            .collect();
+        let scores = variable::scores(
+            &passing_counts,
+            &lib_sizes,
+            args.top_variable_by,
+            args.pseudocount,
+        );
+        for (i, selected) in passing.iter().zip(variable::top_n(&scores, n)) {
+            if !selected {
+                trace!(
                     "{}",
-                    format!(
-                        "gene {} failed filtering (expressed count {} < {})",
-                        gene, gene_nexpressed, min_expressed
-                    )
+                    format!("gene {} is not among the most variable", genes[*i].gene)
                 );
-                gene_filtered = true;
+                gene_reasons[*i].push(FilterReason::TopVariable);
             }
-            _ => (),
         }
+        info!(
+            "{}",
+            format!(
+                "selected the {} most variable of {} genes",
+                n.min(passing.len()),
+                passing.len()
+            )
+        );
+    }
 
-        // Filter on zero variance:
-        if args.filter_identical && counts.iter().all(|i| *i == counts[0]) {
-            debug!(
+    // Write out the gene statistics & removed genes, collecting the passing genes:
+    let mut passed: Vec<&Record> = Vec::new();
+    for ((record, reasons), n_expressed) in
+        genes.iter().zip(gene_reasons.iter()).zip(gene_expressed)
+    {
+        let gene = &record.gene;
+        let counts = &record.counts;
+        if let Some(ref mut g) = gene_stats_output {
+            writeln!(
+                g,
                 "{}",
-                format!("gene {} failed filtering (zero variance)", gene)
-            );
-            gene_filtered = true;
+                stats::format_gene_stats(gene, counts, n_expressed, reasons.is_empty())
+            )?;
         }
-
-        if !gene_filtered {
+        if reasons.is_empty() {
             // Gene passed filtering:
             passed_genes += 1;
             trace!("{}", format!("gene {} passed filtering", gene));
-            println!("{}", line_trimmed);
+            passed.push(record);
             for (i, v) in counts.iter().enumerate() {
                 samples[i].passed_count += v;
                 if v >= &args.expression_threshold {
                     samples[i].passed_expressed += 1;
                 }
             }
+        } else {
+            if reasons.contains(&FilterReason::GeneList) {
+                excluded_genes += 1;
+                for (i, v) in counts.iter().enumerate() {
+                    samples[i].excluded_count += v;
+                }
+            }
+            if let Some(ref mut r) = removed {
+                writeln!(r, "{}\t{}", record.format(), format_reasons(reasons))?;
+            }
+        }
+    }
+    for (s, g) in samples.iter_mut().zip(group_indices.iter()) {
+        s.group_passed = group_passed[*g];
+    }
+    if let Some(r) = removed {
+        r.finish()?;
+    }
+    if let Some(g) = gene_stats_output {
+        g.finish()?;
+    }
+
+    // Calculate any normalisation factors (with log2 CPM implying CPM normalisation):
+    let passed_counts: Vec<&[f64]> = passed.iter().map(|r| r.counts.as_slice()).collect();
+    let normalisation = match args.transform {
+        Some(Transform::Log2Cpm) => Some(Normalisation::Cpm),
+        _ => args.normalise,
+    };
+    let factors = match normalisation {
+        Some(method) => {
+            let factors = normalise::size_factors(&passed_counts, &sample_names, method)?;
+            if let Some(ref p) = args.size_factors_path {
+                let mut f = create_output(p, "size factors")?;
+                let mut header = vec!["sample"];
+                header.extend(normalise::SIZE_FACTOR_COLUMNS);
+                writeln!(f, "{}", header.join("\t"))?;
+                for (i, s) in samples.iter().enumerate() {
+                    writeln!(
+                        f,
+                        "{}\t{}\t{}\t{}",
+                        s.name,
+                        stats::format_stat(factors.lib_sizes[i]),
+                        stats::format_stat(factors.norm_factors[i]),
+                        stats::format_stat(factors.size_factors[i])
+                    )?;
+                }
+                f.finish()?;
+            }
+            Some(factors)
+        }
+        None => None,
+    };
+    let vst = match args.transform {
+        Some(Transform::Vst) => Some(Vst::fit(&passed_counts, &sample_names)?),
+        _ => None,
+    };
+
+    // Write out the passing genes, normalising & transforming them if requested:
+    writeln!(output, "{}", counts_reader.header())?;
+    for record in passed {
+        let mut values = match factors {
+            Some(ref f) => record
+                .counts
+                .iter()
+                .zip(f.size_factors.iter())
+                .map(|(v, s)| v / s)
+                .collect(),
+            None => record.counts.clone(),
+        };
+        match (args.transform, vst.as_ref()) {
+            (Some(Transform::Vst), Some(vst)) => values = vst.transform(&record.counts),
+            (Some(Transform::Asinh), _) => values.iter_mut().for_each(|v| *v = v.asinh()),
+            (Some(_), _) => values
+                .iter_mut()
+                .for_each(|v| *v = (*v + args.pseudocount).log2()),
+            (None, _) => (),
         }
+        let row = Record {
+            gene: record.gene.clone(),
+            annotation: record.annotation.clone(),
+            counts: values,
+        };
+        writeln!(output, "{}", row.format_decimals(args.decimals))?;
     }
 
+    // Complete the filtered counts before any metacounts are written (which may share stdout):
+    output.finish()?;
+
+    Ok(FilterResult {
+        samples,
+        metacount_names,
+        total_genes,
+        passed_genes,
+        excluded_genes,
+        n_annotation_columns: counts_reader.annotation_columns.len(),
+    })
+}
+
+impl Cli {
+    // Find any option given that requires a dense counts matrix:
+    fn dense_only_option(&self) -> Option<&'static str> {
+        if self.gene_stats_path.is_some() {
+            Some("--gene-stats")
+        } else if self.min_cpm.is_some() {
+            Some("--min-cpm")
+        } else if self.filter_by_expr {
+            Some("--filter-by-expr")
+        } else if self.min_group_expressed.is_some() {
+            Some("--min-group-expressed")
+        } else if self.min_group_prop.is_some() {
+            Some("--min-group-prop")
+        } else if self.min_variance.is_some() {
+            Some("--min-variance")
+        } else if self.min_cv.is_some() {
+            Some("--min-cv")
+        } else if self.min_log_variance.is_some() {
+            Some("--min-log-variance")
+        } else if self.top_variable.is_some() {
+            Some("--top-variable")
+        } else if self.normalise.is_some() {
+            Some("--normalise")
+        } else if self.transform.is_some() {
+            Some("--transform")
+        } else if self.decimals.is_some() {
+            Some("--decimals")
+        } else if self.annotate {
+            Some("--annotate")
+        } else if self.min_library_size.is_some() {
+            Some("--min-library-size")
+        } else if self.min_detected_genes.is_some() {
+            Some("--min-detected-genes")
+        } else if self.max_no_feature_fraction.is_some() {
+            Some("--max-no-feature-fraction")
+        } else if self.max_ambiguous_fraction.is_some() {
+            Some("--max-ambiguous-fraction")
+        } else if self.removed_samples_path.is_some() {
+            Some("--removed-samples")
+        } else if self.aggregate_path.is_some() {
+            Some("--aggregate")
+        } else if self.aggregate_gtf {
+            Some("--aggregate-gtf")
+        } else if self.collapse_path.is_some() {
+            Some("--collapse")
+        } else if self.collapse_regex.is_some() {
+            Some("--collapse-regex")
+        } else if self.samples.is_some() {
+            Some("--samples")
+        } else if self.drop_samples.is_some() {
+            Some("--drop-samples")
+        } else if self.rename_path.is_some() {
+            Some("--rename")
+        } else if self.strip_versions {
+            Some("--strip-versions")
+        } else if self.duplicates != DuplicatePolicy::Keep {
+            Some("--duplicates")
+        } else {
+            None
+        }
+    }
+}
+
+fn main() -> Result<(), Error> {
+    // Capture the commandline arguments:
+    let args = Cli::from_args();
+
+    // Register the SIGPIPE actions:
+    let _signal = unsafe {
+        signal_hook::register(signal_hook::SIGPIPE, || {
+            process::exit(128 + signal_hook::SIGPIPE)
+        })
+    };
+
+    // Build the log:
+    if stderrlog::new()
+        .module(module_path!())
+        .verbosity(args.verbose)
+        .init()
+        .is_err()
+    {
+        return Err(Error::other("failed to initialise logger"));
+    }
+
+    // Determine how counts are parsed:
+    let count_mode = CountMode {
+        fractional: args.fractional || args.format.is_estimated(),
+        round: args.round,
+    };
+
+    // The group filters need the sample groups:
+    if args.sample_sheet.is_none()
+        && (args.min_group_expressed.is_some() || args.min_group_prop.is_some())
+    {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "group filters require a --sample-sheet",
+        ));
+    }
+
+    // The size factors need a normalisation method:
+    if args.size_factors_path.is_some()
+        && args.normalise.is_none()
+        && args.transform != Some(Transform::Log2Cpm)
+    {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "--size-factors requires --normalise",
+        ));
+    }
+
+    // The log2 CPM & variance-stabilising transforms normalise the counts themselves:
+    if args.normalise.is_some()
+        && matches!(
+            args.transform,
+            Some(Transform::Log2Cpm) | Some(Transform::Vst)
+        )
+    {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "--transform log2cpm & vst cannot be combined with --normalise",
+        ));
+    }
+
+    // The annotation filters need the gene annotation:
+    if args.gtf_path.is_none()
+        && (!args.keep_biotypes.is_empty()
+            || !args.exclude_chroms.is_empty()
+            || args.min_gene_length.is_some()
+            || args.annotate)
+    {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "annotation filters require a --gtf",
+        ));
+    }
+
+    // Aggregating features needs a mapping to genes:
+    if args.aggregate_gtf && args.gtf_path.is_none() {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "--aggregate-gtf requires a --gtf",
+        ));
+    }
+    if args.drop_unmapped && args.aggregate_path.is_none() && !args.aggregate_gtf {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "--drop-unmapped requires --aggregate or --aggregate-gtf",
+        ));
+    }
+
+    // Read the gene lists:
+    let gene_list = GeneList {
+        include: match args.include_genes {
+            Some(ref p) => Some(genelist::read_gene_ids(p)?),
+            None => None,
+        },
+        exclude: match args.exclude_genes {
+            Some(ref p) => genelist::read_gene_ids(p)?,
+            None => Default::default(),
+        },
+        include_patterns: args.include_patterns.clone(),
+        exclude_patterns: args.exclude_patterns.clone(),
+    };
+
+    // Build the gene filters:
+    let gene_filter = GeneFilter {
+        expression_threshold: args.expression_threshold,
+        min_count: args.min_count,
+        min_expressed: args.min_expressed,
+        min_cpm: args.min_cpm.map(|c| (c, args.min_cpm_samples)),
+        filter_by_expr: if args.filter_by_expr {
+            Some(FilterByExpr {
+                min_count: args.fbe_min_count,
+                min_total_count: args.fbe_min_total_count,
+                large_n: args.fbe_large_n,
+                min_prop: args.fbe_min_prop,
+            })
+        } else {
+            None
+        },
+        min_group_expressed: args.min_group_expressed,
+        min_group_prop: args.min_group_prop,
+        variance_scale: args.variance_scale,
+        min_variance: args.min_variance,
+        min_cv: args.min_cv,
+        min_log_variance: args.min_log_variance,
+        pseudocount: args.pseudocount,
+        filter_identical: args.filter_identical,
+        gene_list,
+        annotation: match args.gtf_path {
+            Some(ref p) => Some(gtf::read_gtf(p, args.strip_versions)?),
+            None => None,
+        },
+        keep_biotypes: args.keep_biotypes.clone(),
+        exclude_chroms: args.exclude_chroms.clone(),
+        min_gene_length: args.min_gene_length,
+        lib_sizes: Vec::new(),
+        groups: Vec::new(),
+        group_sizes: Vec::new(),
+        filter_by_expr_cutoffs: None,
+    };
+
+    // Sort out the metacount destination:
+    let mut metacount_dest = match args.metacount_path {
+        Some(ref p) => {
+            let f = create_output(p, "metacounts")?;
+            MetacountDestination {
+                handle: f,
+                is_stdout: false,
+                prefix: String::from(""),
+                padding: String::new(),
+            }
+        }
+        None => {
+            info!("writing metacounts to stdout");
+            MetacountDestination {
+                handle: Output::stdout(),
+                is_stdout: true,
+                prefix: String::from("__"),
+                padding: String::new(),
+            }
+        }
+    };
+
+    // Filter the counts:
+    let gene_filter_groups = gene_filter.has_group_filters();
+    let gene_list_active = gene_filter.gene_list.is_active();
+    let FilterResult {
+        samples,
+        metacount_names,
+        total_genes,
+        passed_genes,
+        excluded_genes,
+        n_annotation_columns,
+    } = if args.format == InputFormat::Mtx {
+        if let Some(option) = args.dense_only_option() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("{} is not supported for Matrix Market input", option),
+            ));
+        }
+        let (input_path, output_dir) =
+            match (args.paths.as_slice(), args.output_path.as_deref()) {
+                ([p], Some(o)) => (p, o),
+                _ => return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "Matrix Market input requires a single input path and an --output directory",
+                )),
+            };
+        let input_path = match expand_path(input_path) {
+            Some(p) => PathBuf::from(p),
+            None => return Err(Error::new(ErrorKind::NotFound, "input file not found")),
+        };
+        let output_dir = match expand_path(output_dir) {
+            Some(p) => PathBuf::from(p),
+            None => {
+                return Err(Error::new(
+                    ErrorKind::NotFound,
+                    "output directory not found",
+                ))
+            }
+        };
+        mtx::filter_mtx(
+            &input_path,
+            &output_dir,
+            count_mode,
+            &gene_filter,
+            args.expression_threshold,
+            match args.removed_path {
+                Some(ref p) => Some(create_output(p, "removed genes")?),
+                None => None,
+            },
+        )?
+    } else {
+        filter_matrix(&args, count_mode, gene_filter)?
+    };
+
     // Process and write the metacount data:
-    if !metacount_dest.is_stdout {
+    if metacount_dest.is_stdout {
+        metacount_dest.padding = "\tNA".repeat(n_annotation_columns);
+    } else {
         let mut header: Vec<String> = vec!["feature".to_string()];
         header.extend(samples.iter().map(|s| s.name.to_string()));
         writeln!(metacount_dest.handle, "{}", header.join("\t"))?;
     }
     if args.summary_metacounts {
-        write_metacount(&mut metacount_dest, &samples, &"total_count", |s| {
+        write_metacount(&mut metacount_dest, &samples, "total_count", |s| {
             s.total_count
         })?;
-        write_metacount(&mut metacount_dest, &samples, &"passed_count", |s| {
+        write_metacount(&mut metacount_dest, &samples, "passed_count", |s| {
             s.passed_count
         })?;
-        write_metacount(&mut metacount_dest, &samples, &"total_expressed", |s| {
-            s.total_expressed
+        write_metacount(&mut metacount_dest, &samples, "total_expressed", |s| {
+            s.total_expressed as f64
         })?;
-        write_metacount(&mut metacount_dest, &samples, &"passed_expressed", |s| {
-            s.passed_expressed
+        write_metacount(&mut metacount_dest, &samples, "passed_expressed", |s| {
+            s.passed_expressed as f64
         })?;
+        if gene_filter_groups {
+            write_metacount(&mut metacount_dest, &samples, "group_passed", |s| {
+                s.group_passed as f64
+            })?;
+        }
+        if gene_list_active {
+            write_metacount(&mut metacount_dest, &samples, "excluded_count", |s| {
+                s.excluded_count
+            })?;
+        }
     }
     for m in metacount_names.iter().enumerate() {
         let counts = samples
             .iter()
-            .map(|s| s.metacounts[m.0].to_string())
+            .map(|s| reader::format_value(s.metacounts[m.0]))
             .collect::<Vec<String>>()
             .join("\t");
         if metacount_dest.is_stdout {
-            writeln!(metacount_dest.handle, "{}\t{}", m.1, counts)?;
+            writeln!(
+                metacount_dest.handle,
+                "{}{}\t{}",
+                m.1, metacount_dest.padding, counts
+            )?;
         } else {
             writeln!(
                 metacount_dest.handle,
@@ -333,12 +1221,19 @@ fn main() -> Result<(), Error> {
             )?;
         }
     }
+    metacount_dest.handle.finish()?;
 
     // Record the results:
     info!(
         "{}",
         format!("{} / {} genes passed filter", passed_genes, total_genes)
     );
+    if gene_list_active {
+        info!(
+            "{}",
+            format!("{} genes removed by gene lists", excluded_genes)
+        );
+    }
     info!(
         "{}",
         format!("{} metagenes detected", metacount_names.len())
@@ -0,0 +1,268 @@
+use crate::input::{expand_path, open_input};
+use crate::reader::Record;
+use log::*;
+use std::collections::HashSet;
+use std::io::prelude::*;
+use std::io::{Error, ErrorKind, Lines};
+use std::path::{Path, PathBuf};
+
+// File suffixes removed when deriving a sample name from a file name:
+const SAMPLE_SUFFIXES: &[&str] = &[
+    ".gz",
+    ".txt",
+    ".tsv",
+    ".tab",
+    ".counts",
+    ".count",
+    ".htseq",
+    "ReadsPerGene.out",
+    ".sf",
+    "quant",
+    "abundance",
+    ".results",
+    ".genes",
+    ".isoforms",
+];
+
+// A per-sample input file:
+pub struct SampleFile {
+    pub name: String,
+    pub filename: String,
+}
+
+// A function to parse a single per-sample line into its feature ID and count:
+pub type LineParser = Box<dyn Fn(&str) -> Option<(String, f64)>>;
+
+// Derive a sample name from a file name by removing the directory and any count file suffixes.
+// If nothing is left (as for STAR's ReadsPerGene.out.tab), the directory name is used instead:
+fn sample_name(filename: &str) -> String {
+    let path = Path::new(filename);
+    let mut name = path
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_else(|| String::from(filename));
+    while let Some(suffix) = SAMPLE_SUFFIXES.iter().find(|s| name.ends_with(*s)) {
+        name.truncate(name.len() - suffix.len());
+    }
+    let name = name.trim_end_matches(&['_', '.'][..]);
+    if name.is_empty() {
+        if let Some(dir) = path.parent().and_then(|p| p.file_name()) {
+            return dir.to_string_lossy().into_owned();
+        }
+    }
+    String::from(name)
+}
+
+// Expand a single input path, treating it as a glob pattern if it contains glob characters:
+fn expand_input(path: &Path) -> Result<Vec<String>, Error> {
+    let filename = match expand_path(path) {
+        Some(f) => f,
+        None => return Err(Error::new(ErrorKind::NotFound, "input file not found")),
+    };
+    if !filename.contains(&['*', '?', '['][..]) {
+        return Ok(vec![filename]);
+    }
+    let pattern = glob::glob(&filename).map_err(|e| {
+        Error::new(
+            ErrorKind::InvalidInput,
+            format!("invalid input pattern {}: {}", filename, e),
+        )
+    })?;
+    let mut filenames = Vec::new();
+    for entry in pattern {
+        let p = entry.map_err(Error::from)?;
+        filenames.push(p.to_string_lossy().into_owned());
+    }
+    if filenames.is_empty() {
+        return Err(Error::new(
+            ErrorKind::NotFound,
+            format!("no input files match {}", filename),
+        ));
+    }
+    Ok(filenames)
+}
+
+// Read a file list containing one input file per line, optionally followed by a tab and sample name:
+fn read_file_list(path: &Path) -> Result<Vec<SampleFile>, Error> {
+    let list_filename = match expand_path(path) {
+        Some(f) => f,
+        None => return Err(Error::new(ErrorKind::NotFound, "file list not found")),
+    };
+    let mut files = Vec::new();
+    for line in open_input(&list_filename)?.lines() {
+        let line = line?;
+        let line_trimmed = line.trim();
+        if line_trimmed.is_empty() || line_trimmed.starts_with('#') {
+            continue;
+        }
+        let mut line_data = line_trimmed.split('\t');
+        let filename = match expand_path(Path::new(line_data.next().unwrap_or_default())) {
+            Some(f) => f,
+            None => return Err(Error::new(ErrorKind::NotFound, "input file not found")),
+        };
+        let name = match line_data.next() {
+            Some(n) if !n.is_empty() => String::from(n),
+            _ => sample_name(&filename),
+        };
+        files.push(SampleFile { name, filename });
+    }
+    Ok(files)
+}
+
+// Resolve the per-sample input files from the commandline paths and optional file list:
+pub fn sample_files(paths: &[PathBuf], file_list: Option<&Path>) -> Result<Vec<SampleFile>, Error> {
+    let mut files = match file_list {
+        Some(p) => read_file_list(p)?,
+        None => Vec::new(),
+    };
+    for path in paths {
+        if path == Path::new("-") {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "per-sample inputs cannot be read from stdin",
+            ));
+        }
+        for filename in expand_input(path)? {
+            files.push(SampleFile {
+                name: sample_name(&filename),
+                filename,
+            });
+        }
+    }
+    if files.is_empty() {
+        return Err(Error::new(ErrorKind::InvalidInput, "no input files given"));
+    }
+    let mut names = HashSet::new();
+    for f in files.iter() {
+        if !names.insert(f.name.as_str()) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("duplicate sample name {}", f.name),
+            ));
+        }
+    }
+    Ok(files)
+}
+
+// An open per-sample input:
+struct OpenSample {
+    filename: String,
+    lines: Lines<Box<dyn BufRead>>,
+    line_number: usize,
+}
+
+impl OpenSample {
+    // Read the next non-empty line:
+    fn next_line(&mut self) -> Option<Result<String, Error>> {
+        for line in &mut self.lines {
+            self.line_number += 1;
+            match line {
+                Ok(l) if l.trim().is_empty() => continue,
+                other => return Some(other),
+            }
+        }
+        None
+    }
+}
+
+// An iterator over the rows formed by reading a set of per-sample files in step:
+struct MergedRecords {
+    samples: Vec<OpenSample>,
+    parse: LineParser,
+}
+
+impl MergedRecords {
+    fn merge_next(&mut self) -> Result<Option<Record>, Error> {
+        let mut gene: Option<String> = None;
+        let mut counts = Vec::with_capacity(self.samples.len());
+        let mut finished = Vec::new();
+        for sample in self.samples.iter_mut() {
+            let line = match sample.next_line() {
+                Some(l) => l?,
+                None => {
+                    finished.push(sample.filename.as_str());
+                    continue;
+                }
+            };
+            let (sample_gene, count) = match (self.parse)(line.trim()) {
+                Some(p) => p,
+                None => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!(
+                            "failed to parse line {} of {}",
+                            sample.line_number, sample.filename
+                        ),
+                    ))
+                }
+            };
+            match gene {
+                Some(ref g) if *g != sample_gene => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!(
+                            "feature mismatch at line {} of {} (expected {}, found {})",
+                            sample.line_number, sample.filename, g, sample_gene
+                        ),
+                    ))
+                }
+                Some(_) => (),
+                None => gene = Some(sample_gene),
+            }
+            counts.push(count);
+        }
+        match gene {
+            None => Ok(None),
+            Some(_) if !finished.is_empty() => Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "{} contains fewer features than the other inputs",
+                    finished[0]
+                ),
+            )),
+            Some(gene) => Ok(Some(Record {
+                gene,
+                annotation: Vec::new(),
+                counts,
+            })),
+        }
+    }
+}
+
+impl Iterator for MergedRecords {
+    type Item = Result<Record, Error>;
+    fn next(&mut self) -> Option<Self::Item> {
+        self.merge_next().transpose()
+    }
+}
+
+// Open a set of per-sample files (skipping the given number of header lines in each) and
+// merge their rows, checking that every file lists the same features in the same order:
+pub fn merge_samples(
+    files: &[SampleFile],
+    header_lines: usize,
+    parse: LineParser,
+) -> Result<Box<dyn Iterator<Item = Result<Record, Error>>>, Error> {
+    let mut samples = Vec::with_capacity(files.len());
+    for f in files.iter() {
+        info!(
+            "{}",
+            format!("reading sample {} from {}", f.name, f.filename)
+        );
+        let mut sample = OpenSample {
+            filename: f.filename.clone(),
+            lines: open_input(&f.filename)?.lines(),
+            line_number: 0,
+        };
+        for _ in 0..header_lines {
+            if sample.next_line().transpose()?.is_none() {
+                return Err(Error::new(
+                    ErrorKind::UnexpectedEof,
+                    format!("failed to read header of {}", f.filename),
+                ));
+            }
+        }
+        samples.push(sample);
+    }
+    Ok(Box::new(MergedRecords { samples, parse }))
+}
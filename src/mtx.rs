@@ -0,0 +1,390 @@
+use crate::filter::{format_reasons, FilterReason, GeneFilter, GeneStats};
+use crate::input::open_input;
+use crate::output::Output;
+use crate::reader::CountMode;
+use crate::{FilterResult, Sample};
+use log::*;
+use std::fs;
+use std::io::prelude::*;
+use std::io::{Error, ErrorKind, Lines};
+use std::path::{Path, PathBuf};
+
+// The file names making up a Matrix Market directory (each may also be gzip compressed):
+const MATRIX_NAMES: &[&str] = &["matrix.mtx"];
+const FEATURE_NAMES: &[&str] = &["features.tsv", "genes.tsv"];
+const BARCODE_NAMES: &[&str] = &["barcodes.tsv"];
+
+// The column names of the features file (as 10x Genomics' features.tsv), used for the header of
+// the removed output:
+const FEATURE_COLUMNS: &[&str] = &["gene", "gene_name", "feature_type"];
+
+// The Matrix Market header for the only supported layout:
+const MTX_HEADER: &str = "%%MatrixMarket matrix coordinate";
+
+// The files making up a Matrix Market directory:
+struct MtxFiles {
+    matrix: PathBuf,
+    features: PathBuf,
+    barcodes: PathBuf,
+}
+
+// Per-gene accumulators for the first pass over the matrix:
+#[derive(Clone, Default)]
+struct GeneAccumulator {
+    total: f64,
+    n_expressed: u64,
+    n_stored: u64,
+    min: f64,
+    max: f64,
+}
+
+// Find one of a set of named files (optionally gzip compressed) in a directory:
+fn find_file(dir: &Path, names: &[&str]) -> Result<PathBuf, Error> {
+    for name in names.iter() {
+        for candidate in [String::from(*name), format!("{}.gz", name)].iter() {
+            let path = dir.join(candidate);
+            if path.is_file() {
+                return Ok(path);
+            }
+        }
+    }
+    Err(Error::new(
+        ErrorKind::NotFound,
+        format!("failed to find {} in {}", names[0], dir.display()),
+    ))
+}
+
+// Locate the Matrix Market files, given either their directory or the matrix file itself:
+fn find_files(path: &Path) -> Result<MtxFiles, Error> {
+    let dir = if path.is_dir() {
+        path
+    } else {
+        path.parent().unwrap_or_else(|| Path::new("."))
+    };
+    Ok(MtxFiles {
+        matrix: if path.is_dir() {
+            find_file(dir, MATRIX_NAMES)?
+        } else {
+            path.to_path_buf()
+        },
+        features: find_file(dir, FEATURE_NAMES)?,
+        barcodes: find_file(dir, BARCODE_NAMES)?,
+    })
+}
+
+// Read the non-empty lines of a (possibly compressed) file:
+fn read_lines(path: &Path) -> Result<Vec<String>, Error> {
+    let mut lines = Vec::new();
+    for line in open_input(&path.to_string_lossy())?.lines() {
+        let line = line?;
+        if !line.trim().is_empty() {
+            lines.push(line);
+        }
+    }
+    Ok(lines)
+}
+
+// The error for a matrix with more than one entry for the same row & column:
+fn duplicate_error(path: &Path) -> Error {
+    Error::new(
+        ErrorKind::InvalidData,
+        format!("duplicate Matrix Market entries in {}", path.display()),
+    )
+}
+
+// An open Matrix Market file, positioned at its first entry:
+struct MtxReader {
+    header: String,
+    n_rows: usize,
+    n_cols: usize,
+    n_entries: usize,
+    lines: Lines<Box<dyn BufRead>>,
+    mode: CountMode,
+}
+
+impl MtxReader {
+    // Open a Matrix Market file and read its header & size line:
+    fn open(path: &Path, mode: CountMode) -> Result<MtxReader, Error> {
+        let invalid = |msg: &str| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("{} in {}", msg, path.display()),
+            )
+        };
+        let mut lines = open_input(&path.to_string_lossy())?.lines();
+        let header = match lines.next() {
+            Some(h) => h?,
+            None => return Err(invalid("missing Matrix Market header")),
+        };
+        let header_data: Vec<_> = header.split_whitespace().collect();
+        if !header.starts_with(MTX_HEADER) || header_data.get(4) != Some(&"general") {
+            return Err(invalid("unsupported Matrix Market layout"));
+        }
+        if header_data.get(3) == Some(&"pattern") {
+            return Err(invalid("Matrix Market pattern matrices are not supported"));
+        }
+        let size_line = loop {
+            match lines.next() {
+                Some(l) => {
+                    let l = l?;
+                    if !l.starts_with('%') && !l.trim().is_empty() {
+                        break l;
+                    }
+                }
+                None => return Err(invalid("missing Matrix Market size line")),
+            }
+        };
+        let size: Vec<usize> = match size_line
+            .split_whitespace()
+            .map(|v| v.parse::<usize>())
+            .collect()
+        {
+            Ok(s) => s,
+            Err(_) => return Err(invalid("invalid Matrix Market size line")),
+        };
+        if size.len() != 3 {
+            return Err(invalid("invalid Matrix Market size line"));
+        }
+        Ok(MtxReader {
+            header,
+            n_rows: size[0],
+            n_cols: size[1],
+            n_entries: size[2],
+            lines,
+            mode,
+        })
+    }
+
+    // Read the next entry as zero-based (row, column, value):
+    fn next_entry(&mut self) -> Option<Result<(usize, usize, f64), Error>> {
+        for line in &mut self.lines {
+            let line = match line {
+                Ok(l) => l,
+                Err(e) => return Some(Err(e)),
+            };
+            let line_data: Vec<_> = line.split_whitespace().collect();
+            if line_data.is_empty() || line_data[0].starts_with('%') {
+                continue;
+            }
+            let entry = match (
+                line_data.first().and_then(|v| v.parse::<usize>().ok()),
+                line_data.get(1).and_then(|v| v.parse::<usize>().ok()),
+                line_data.get(2).and_then(|v| self.mode.parse(v)),
+            ) {
+                (Some(i), Some(j), Some(v))
+                    if i >= 1 && i <= self.n_rows && j >= 1 && j <= self.n_cols =>
+                {
+                    (i - 1, j - 1, v)
+                }
+                _ => {
+                    return Some(Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!("invalid Matrix Market entry {}", line.trim()),
+                    )))
+                }
+            };
+            return Some(Ok(entry));
+        }
+        None
+    }
+}
+
+// Filter the genes of a Matrix Market matrix, writing the matrix, features & barcodes of the
+// passing genes to the output directory (and the features & reasons of any removed genes to
+// the removed output). The matrix is read twice (once to calculate the
+// gene statistics and once to write the passing entries), so it is never held in memory:
+pub fn filter_mtx(
+    input_path: &Path,
+    output_dir: &Path,
+    mode: CountMode,
+    gene_filter: &GeneFilter,
+    expression_threshold: f64,
+    mut removed: Option<Output>,
+) -> Result<FilterResult, Error> {
+    let files = find_files(input_path)?;
+    info!(
+        "{}",
+        format!(
+            "reading Matrix Market counts from {}",
+            files.matrix.display()
+        )
+    );
+    let features = read_lines(&files.features)?;
+    let barcodes = read_lines(&files.barcodes)?;
+
+    // First pass: accumulate the gene & sample totals:
+    let mut reader = MtxReader::open(&files.matrix, mode)?;
+    if features.len() != reader.n_rows || barcodes.len() != reader.n_cols {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "matrix size {} x {} does not match {} features & {} barcodes",
+                reader.n_rows,
+                reader.n_cols,
+                features.len(),
+                barcodes.len()
+            ),
+        ));
+    }
+    let mut samples: Vec<Sample> = barcodes
+        .iter()
+        .map(|b| Sample::new(String::from(b.split('\t').next().unwrap_or_default())))
+        .collect();
+    let mut genes = vec![GeneAccumulator::default(); reader.n_rows];
+    let mut sample_stored = vec![0u64; reader.n_cols];
+    let mut previous = None;
+    while let Some(entry) = reader.next_entry() {
+        let (i, j, v) = entry?;
+        if previous == Some((i, j)) {
+            return Err(duplicate_error(&files.matrix));
+        }
+        previous = Some((i, j));
+        let gene = &mut genes[i];
+        if gene.n_stored == 0 || v < gene.min {
+            gene.min = v;
+        }
+        if gene.n_stored == 0 || v > gene.max {
+            gene.max = v;
+        }
+        gene.total += v;
+        gene.n_stored += 1;
+        sample_stored[j] += 1;
+        samples[j].total_count += v;
+        if v >= expression_threshold {
+            gene.n_expressed += 1;
+            samples[j].total_expressed += 1;
+        }
+    }
+
+    // Duplicate entries (that were not adjacent) can leave a gene or sample with more stored
+    // entries than the matrix has room for:
+    if genes.iter().any(|g| g.n_stored > reader.n_cols as u64)
+        || sample_stored.iter().any(|n| *n > reader.n_rows as u64)
+    {
+        return Err(duplicate_error(&files.matrix));
+    }
+
+    // Unstored entries are zero, and so are expressed only for a non-positive threshold:
+    let zero_expressed = 0.0 >= expression_threshold;
+    if zero_expressed {
+        for (s, n) in samples.iter_mut().zip(sample_stored.iter()) {
+            s.total_expressed += reader.n_rows as u64 - n;
+        }
+    }
+
+    // Filter the genes, recording the new (zero-based) row index of each passing gene:
+    let n_cols = reader.n_cols as u64;
+    let mut new_rows: Vec<Option<usize>> = Vec::with_capacity(reader.n_rows);
+    let mut passed_entries = 0;
+    let mut passed_genes = 0;
+    let mut excluded = vec![false; reader.n_rows];
+    if let Some(ref mut r) = removed {
+        let n_columns = features.first().map(|f| f.split('\t').count()).unwrap_or(1);
+        let mut header: Vec<String> = (0..n_columns)
+            .map(|i| match FEATURE_COLUMNS.get(i) {
+                Some(c) => String::from(*c),
+                None => format!("column_{}", i + 1),
+            })
+            .collect();
+        header.push(String::from("reason"));
+        writeln!(r, "{}", header.join("\t"))?;
+    }
+    for (feature, gene) in features.iter().zip(genes.iter()) {
+        let n_zero = n_cols - gene.n_stored;
+        let gene_stats = GeneStats {
+            n_samples: reader.n_cols,
+            total: gene.total,
+            n_expressed: gene.n_expressed + if zero_expressed { n_zero } else { 0 },
+            identical: if n_zero == 0 {
+                gene.min == gene.max
+            } else {
+                gene.max == 0.0
+            },
+        };
+        let gene_id = feature.split('\t').next().unwrap_or_default();
+        let reasons = gene_filter.test(gene_id, &[], &gene_stats);
+        if reasons.is_empty() {
+            trace!("{}", format!("gene {} passed filtering", gene_id));
+            new_rows.push(Some(passed_genes));
+            passed_genes += 1;
+            passed_entries += gene.n_stored;
+        } else {
+            excluded[new_rows.len()] = reasons.contains(&FilterReason::GeneList);
+            if let Some(ref mut r) = removed {
+                writeln!(r, "{}\t{}", feature, format_reasons(&reasons))?;
+            }
+            new_rows.push(None);
+        }
+    }
+    if let Some(r) = removed {
+        r.finish()?;
+    }
+
+    // Second pass: write the entries of the passing genes:
+    fs::create_dir_all(output_dir)?;
+    let output_file = |path: &Path| {
+        let filename = output_dir.join(path.file_name().unwrap_or_default());
+        info!("{}", format!("writing {}", filename.display()));
+        Output::create(&filename.to_string_lossy())
+    };
+    let mut matrix_output = output_file(&files.matrix)?;
+    let mut features_output = output_file(&files.features)?;
+    let mut barcodes_output = output_file(&files.barcodes)?;
+    let mut reader = MtxReader::open(&files.matrix, mode)?;
+    writeln!(matrix_output, "{}", reader.header)?;
+    writeln!(
+        matrix_output,
+        "{} {} {}",
+        passed_genes, reader.n_cols, passed_entries
+    )?;
+    let mut passed_stored = vec![0u64; reader.n_cols];
+    let mut n_read = 0;
+    while let Some(entry) = reader.next_entry() {
+        let (i, j, v) = entry?;
+        n_read += 1;
+        if let Some(new_row) = new_rows[i] {
+            writeln!(matrix_output, "{} {} {}", new_row + 1, j + 1, v)?;
+            passed_stored[j] += 1;
+            samples[j].passed_count += v;
+            if v >= expression_threshold {
+                samples[j].passed_expressed += 1;
+            }
+        } else if excluded[i] {
+            samples[j].excluded_count += v;
+        }
+    }
+    if n_read != reader.n_entries {
+        warn!(
+            "{}",
+            format!(
+                "read {} Matrix Market entries (expected {})",
+                n_read, reader.n_entries
+            )
+        );
+    }
+    if zero_expressed {
+        for (s, n) in samples.iter_mut().zip(passed_stored.iter()) {
+            s.passed_expressed += passed_genes as u64 - n;
+        }
+    }
+    for (feature, new_row) in features.iter().zip(new_rows.iter()) {
+        if new_row.is_some() {
+            writeln!(features_output, "{}", feature)?;
+        }
+    }
+    for barcode in barcodes.iter() {
+        writeln!(barcodes_output, "{}", barcode)?;
+    }
+    matrix_output.finish()?;
+    features_output.finish()?;
+    barcodes_output.finish()?;
+
+    Ok(FilterResult {
+        samples,
+        metacount_names: Vec::new(),
+        total_genes: reader.n_rows as u64,
+        passed_genes: passed_genes as u64,
+        excluded_genes: excluded.iter().filter(|e| **e).count() as u64,
+        n_annotation_columns: 0,
+    })
+}
@@ -0,0 +1,235 @@
+use crate::stats;
+use std::io::{Error, ErrorKind};
+use std::str::FromStr;
+
+// The TMM trimming proportions (for the log ratios & the mean log expression), as edgeR's defaults:
+const TMM_LOG_RATIO_TRIM: f64 = 0.3;
+const TMM_SUM_TRIM: f64 = 0.05;
+
+// The supported normalisation methods:
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Normalisation {
+    Cpm,
+    Tmm,
+    Uq,
+    Mor,
+}
+
+impl Normalisation {
+    pub const NAMES: &'static [&'static str] = &["cpm", "tmm", "uq", "mor"];
+}
+
+impl FromStr for Normalisation {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "cpm" => Ok(Normalisation::Cpm),
+            "tmm" => Ok(Normalisation::Tmm),
+            "uq" => Ok(Normalisation::Uq),
+            "mor" => Ok(Normalisation::Mor),
+            _ => Err(format!("unknown normalisation method {}", s)),
+        }
+    }
+}
+
+// The per-sample normalisation factors. Normalised values are the counts divided by the size
+// factors. For CPM, TMM & upper-quartile normalisation, the size factor is the effective library
+// size (the library size scaled by the normalisation factor) in millions; for median-of-ratios
+// normalisation it is the DESeq2 size factor (and there is no normalisation factor):
+pub struct SizeFactors {
+    pub lib_sizes: Vec<f64>,
+    pub norm_factors: Vec<f64>,
+    pub size_factors: Vec<f64>,
+}
+
+// The table column names (following the sample name):
+pub const SIZE_FACTOR_COLUMNS: &[&str] = &["lib_size", "norm_factor", "size_factor"];
+
+// Extract a single sample's counts:
+fn column(genes: &[&[f64]], j: usize) -> Vec<f64> {
+    genes.iter().map(|c| c[j]).collect()
+}
+
+// Scale a set of factors to have a geometric mean of one:
+fn scale_geometric(factors: Vec<f64>) -> Vec<f64> {
+    let log_mean = stats::mean(&factors.iter().map(|f| f.ln()).collect::<Vec<f64>>());
+    factors.iter().map(|f| f / log_mean.exp()).collect()
+}
+
+// The upper quartile of each sample's counts, as a proportion of its library size:
+fn upper_quartiles(genes: &[&[f64]], lib_sizes: &[f64]) -> Vec<f64> {
+    lib_sizes
+        .iter()
+        .enumerate()
+        .map(|(j, l)| stats::quantile(&column(genes, j), 0.75) / l)
+        .collect()
+}
+
+// The TMM factor of one sample against the reference sample (as edgeR's calcFactorTMM):
+fn tmm_factor(obs: &[f64], reference: &[f64], lib_obs: f64, lib_ref: f64) -> f64 {
+    let mut log_ratios = Vec::with_capacity(obs.len());
+    let mut abs_expression = Vec::with_capacity(obs.len());
+    let mut variances = Vec::with_capacity(obs.len());
+    for (o, r) in obs.iter().zip(reference.iter()) {
+        let log_ratio = ((o / lib_obs) / (r / lib_ref)).log2();
+        let abs_e = ((o / lib_obs).log2() + (r / lib_ref).log2()) / 2.0;
+        if log_ratio.is_finite() && abs_e.is_finite() {
+            log_ratios.push(log_ratio);
+            abs_expression.push(abs_e);
+            variances.push((lib_obs - o) / lib_obs / o + (lib_ref - r) / lib_ref / r);
+        }
+    }
+    if log_ratios.iter().all(|l| l.abs() < 1e-6) {
+        return 1.0;
+    }
+
+    // Trim the genes by log ratio & mean log expression, taking the weighted mean of the rest:
+    let n = log_ratios.len() as f64;
+    let lo_l = (n * TMM_LOG_RATIO_TRIM).floor() + 1.0;
+    let hi_l = n + 1.0 - lo_l;
+    let lo_s = (n * TMM_SUM_TRIM).floor() + 1.0;
+    let hi_s = n + 1.0 - lo_s;
+    let ratio_ranks = stats::rank(&log_ratios);
+    let abs_ranks = stats::rank(&abs_expression);
+    let (mut weighted, mut weights) = (0.0, 0.0);
+    for i in 0..log_ratios.len() {
+        if ratio_ranks[i] >= lo_l
+            && ratio_ranks[i] <= hi_l
+            && abs_ranks[i] >= lo_s
+            && abs_ranks[i] <= hi_s
+        {
+            weighted += log_ratios[i] / variances[i];
+            weights += 1.0 / variances[i];
+        }
+    }
+    let f = weighted / weights;
+    if f.is_finite() {
+        f.exp2()
+    } else {
+        1.0
+    }
+}
+
+// The TMM normalisation factors, using the sample whose upper quartile is closest to the mean
+// upper quartile as the reference (as edgeR's calcNormFactors):
+fn tmm_factors(genes: &[&[f64]], lib_sizes: &[f64]) -> Vec<f64> {
+    let f75 = upper_quartiles(genes, lib_sizes);
+    let reference = if stats::median(&f75) < 1e-20 {
+        (0..lib_sizes.len())
+            .map(|j| column(genes, j).iter().map(|v| v.sqrt()).sum::<f64>())
+            .enumerate()
+            .fold(
+                (0, f64::NEG_INFINITY),
+                |m, (j, s)| if s > m.1 { (j, s) } else { m },
+            )
+            .0
+    } else {
+        let mean_f75 = stats::mean(&f75);
+        f75.iter()
+            .map(|f| (f - mean_f75).abs())
+            .enumerate()
+            .fold(
+                (0, f64::INFINITY),
+                |m, (j, d)| if d < m.1 { (j, d) } else { m },
+            )
+            .0
+    };
+    let reference_counts = column(genes, reference);
+    let factors = (0..lib_sizes.len())
+        .map(|j| {
+            tmm_factor(
+                &column(genes, j),
+                &reference_counts,
+                lib_sizes[j],
+                lib_sizes[reference],
+            )
+        })
+        .collect();
+    scale_geometric(factors)
+}
+
+// The DESeq2 median-of-ratios size factors: the median ratio of each sample's counts to the
+// genes' geometric means, using only the genes with a non-zero count in every sample. As DESeq2,
+// there must be at least one such gene:
+fn median_of_ratios(genes: &[&[f64]], n_samples: usize) -> Result<Vec<f64>, Error> {
+    let log_geo_means: Vec<f64> = genes
+        .iter()
+        .map(|c| stats::mean(&c.iter().map(|v| v.ln()).collect::<Vec<f64>>()))
+        .collect();
+    if !log_geo_means.iter().any(|g| g.is_finite()) {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "every passing gene has a zero count in at least one sample, so the median-of-ratios size factors cannot be calculated",
+        ));
+    }
+    Ok((0..n_samples)
+        .map(|j| {
+            let log_ratios: Vec<f64> = genes
+                .iter()
+                .zip(log_geo_means.iter())
+                .filter(|(c, g)| g.is_finite() && c[j] > 0.0)
+                .map(|(c, g)| c[j].ln() - g)
+                .collect();
+            stats::median(&log_ratios).exp()
+        })
+        .collect())
+}
+
+// Calculate the size factors for a set of (passing) genes in the given samples. The library sizes
+// are the sample totals of these genes, and genes with a zero count in every sample are ignored.
+// Every sample must have a non-zero library size:
+pub fn size_factors(
+    genes: &[&[f64]],
+    samples: &[String],
+    method: Normalisation,
+) -> Result<SizeFactors, Error> {
+    let n_samples = samples.len();
+    if genes.is_empty() {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "no genes passed filtering to calculate the size factors from",
+        ));
+    }
+    let lib_sizes: Vec<f64> = (0..n_samples)
+        .map(|j| genes.iter().map(|c| c[j]).sum())
+        .collect();
+    if let Some(j) = lib_sizes.iter().position(|l| *l <= 0.0) {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("sample {} has no counts in the passing genes", samples[j]),
+        ));
+    }
+    let expressed: Vec<&[f64]> = genes
+        .iter()
+        .filter(|c| c.iter().any(|v| *v > 0.0))
+        .copied()
+        .collect();
+    let norm_factors = match method {
+        Normalisation::Cpm => vec![1.0; n_samples],
+        Normalisation::Tmm => tmm_factors(&expressed, &lib_sizes),
+        Normalisation::Uq => {
+            let quartiles = upper_quartiles(&expressed, &lib_sizes);
+            if let Some(j) = quartiles.iter().position(|q| *q <= 0.0) {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("sample {} has an upper quartile of zero", samples[j]),
+                ));
+            }
+            scale_geometric(quartiles)
+        }
+        Normalisation::Mor => vec![f64::NAN; n_samples],
+    };
+    let size_factors = match method {
+        Normalisation::Mor => median_of_ratios(&expressed, n_samples)?,
+        _ => lib_sizes
+            .iter()
+            .zip(norm_factors.iter())
+            .map(|(l, f)| l * f / 1e6)
+            .collect(),
+    };
+    Ok(SizeFactors {
+        lib_sizes,
+        norm_factors,
+        size_factors,
+    })
+}
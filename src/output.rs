@@ -0,0 +1,124 @@
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::fs::{self, File};
+use std::io::prelude::*;
+use std::io::{stdout, BufWriter, Error, Stdout};
+use std::path::PathBuf;
+use std::process;
+
+// The underlying writer for an output:
+enum Sink {
+    Stdout(BufWriter<Stdout>),
+    Plain(BufWriter<File>),
+    Gzip(GzEncoder<BufWriter<File>>),
+}
+
+// Wrap a file, gzip compressing it if the output name ends in ".gz":
+fn sink(file: BufWriter<File>, filename: &str) -> Sink {
+    if filename.ends_with(".gz") {
+        Sink::Gzip(GzEncoder::new(file, Compression::default()))
+    } else {
+        Sink::Plain(file)
+    }
+}
+
+// A buffered output. Files are written to a temporary path and only renamed into place
+// when finish() is called, so a failed run never leaves a half-written output behind:
+pub struct Output {
+    sink: Sink,
+    paths: Option<(PathBuf, PathBuf)>,
+}
+
+impl Output {
+    // Create an output writing to stdout:
+    pub fn stdout() -> Output {
+        Output {
+            sink: Sink::Stdout(BufWriter::new(stdout())),
+            paths: None,
+        }
+    }
+
+    // Create an output writing to a file, gzip compressing it if the name ends in ".gz".
+    // Symlinks to regular files are resolved, so that the target is replaced. Existing paths
+    // that are not regular files (such as /dev/stdout, named pipes or dangling symlinks) are
+    // written directly, as replacing them by renaming would break them:
+    pub fn create(filename: &str) -> Result<Output, Error> {
+        let mut path = PathBuf::from(filename);
+        let is_direct = match fs::metadata(&path) {
+            Ok(m) if m.is_file() => {
+                path = fs::canonicalize(&path)?;
+                false
+            }
+            Ok(_) => true,
+            Err(_) => fs::symlink_metadata(&path).is_ok(),
+        };
+        if is_direct {
+            return Ok(Output {
+                sink: sink(BufWriter::new(File::create(&path)?), filename),
+                paths: None,
+            });
+        }
+        let temp_name = format!(
+            ".{}.{}.tmp",
+            path.file_name()
+                .map(|n| n.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            process::id()
+        );
+        let temp_path = path.with_file_name(temp_name);
+        Ok(Output {
+            sink: sink(BufWriter::new(File::create(&temp_path)?), filename),
+            paths: Some((temp_path, path)),
+        })
+    }
+
+    // Flush all data and move the output file into place:
+    pub fn finish(mut self) -> Result<(), Error> {
+        let file = match self.sink {
+            Sink::Stdout(ref mut s) => return s.flush(),
+            Sink::Plain(ref mut f) => f,
+            Sink::Gzip(ref mut g) => {
+                g.try_finish()?;
+                g.get_mut()
+            }
+        };
+        file.flush()?;
+        if self.paths.is_some() {
+            file.get_ref().sync_all()?;
+        }
+        if let Some((temp_path, path)) = self.paths.take() {
+            if let Err(e) = fs::rename(&temp_path, &path) {
+                let _ = fs::remove_file(&temp_path);
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        match self.sink {
+            Sink::Stdout(ref mut s) => s.write(buf),
+            Sink::Plain(ref mut f) => f.write(buf),
+            Sink::Gzip(ref mut g) => g.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        match self.sink {
+            Sink::Stdout(ref mut s) => s.flush(),
+            Sink::Plain(ref mut f) => f.flush(),
+            Sink::Gzip(ref mut g) => g.flush(),
+        }
+    }
+}
+
+// Remove the temporary file of any output that was not finished:
+impl Drop for Output {
+    fn drop(&mut self) {
+        if let Some((ref temp_path, _)) = self.paths {
+            let _ = fs::remove_file(temp_path);
+        }
+    }
+}
@@ -0,0 +1,37 @@
+use crate::merge::{merge_samples, SampleFile};
+use crate::reader::{CountMode, CountsReader, InputFormat};
+use std::io::Error;
+
+// The ID column name & (zero-based) estimated count column for each quantifier's output:
+fn quant_columns(format: InputFormat) -> (&'static str, usize) {
+    match format {
+        // Name, Length, EffectiveLength, TPM, NumReads:
+        InputFormat::Salmon => ("Name", 4),
+        // target_id, length, eff_length, est_counts, tpm:
+        InputFormat::Kallisto => ("target_id", 3),
+        // gene_id, transcript_id(s), length, effective_length, expected_count, TPM, FPKM:
+        InputFormat::Rsem => ("gene_id", 4),
+        _ => unreachable!(),
+    }
+}
+
+// Read a set of per-sample salmon quant.sf, kallisto abundance.tsv or RSEM results files
+// as a single matrix of estimated counts:
+pub fn read_quant(
+    files: &[SampleFile],
+    format: InputFormat,
+    mode: CountMode,
+) -> Result<CountsReader, Error> {
+    let (id_column, column) = quant_columns(format);
+    let parse = move |line: &str| {
+        let line_data: Vec<_> = line.split('\t').collect();
+        let count = mode.parse(line_data.get(column)?)?;
+        Some((String::from(line_data[0]), count))
+    };
+    Ok(CountsReader {
+        id_column: String::from(id_column),
+        annotation_columns: Vec::new(),
+        samples: files.iter().map(|f| f.name.clone()).collect(),
+        records: merge_samples(files, 1, Box::new(parse))?,
+    })
+}
@@ -0,0 +1,253 @@
+use log::*;
+use std::io::prelude::*;
+use std::io::{Error, ErrorKind, Lines};
+use std::str::FromStr;
+
+// The supported input formats:
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InputFormat {
+    Matrix,
+    Htseq,
+    FeatureCounts,
+    Star,
+    Salmon,
+    Kallisto,
+    Rsem,
+    Mtx,
+}
+
+impl InputFormat {
+    pub const NAMES: &'static [&'static str] = &[
+        "matrix",
+        "htseq",
+        "featurecounts",
+        "star",
+        "salmon",
+        "kallisto",
+        "rsem",
+        "mtx",
+    ];
+
+    // Whether the format is read from a set of per-sample files:
+    pub fn is_per_sample(self) -> bool {
+        !matches!(
+            self,
+            InputFormat::Matrix | InputFormat::FeatureCounts | InputFormat::Mtx
+        )
+    }
+
+    // Whether the format holds (fractional) estimated counts:
+    pub fn is_estimated(self) -> bool {
+        matches!(
+            self,
+            InputFormat::Salmon | InputFormat::Kallisto | InputFormat::Rsem
+        )
+    }
+}
+
+impl FromStr for InputFormat {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "matrix" => Ok(InputFormat::Matrix),
+            "htseq" => Ok(InputFormat::Htseq),
+            "featurecounts" => Ok(InputFormat::FeatureCounts),
+            "star" => Ok(InputFormat::Star),
+            "salmon" => Ok(InputFormat::Salmon),
+            "kallisto" => Ok(InputFormat::Kallisto),
+            "rsem" => Ok(InputFormat::Rsem),
+            "mtx" => Ok(InputFormat::Mtx),
+            _ => Err(format!("unknown input format {}", s)),
+        }
+    }
+}
+
+// How count values are parsed. Unless fractional counts are allowed, any count that is not
+// a non-negative integer is rejected. If rounding is requested, counts are rounded to the
+// nearest integer after parsing:
+#[derive(Debug, Clone, Copy)]
+pub struct CountMode {
+    pub fractional: bool,
+    pub round: bool,
+}
+
+impl CountMode {
+    // Parse a single count:
+    pub fn parse(self, s: &str) -> Option<f64> {
+        let count = if self.fractional {
+            s.parse::<f64>()
+                .ok()
+                .filter(|c| c.is_finite() && *c >= 0.0)?
+        } else {
+            s.parse::<u64>().ok()? as f64
+        };
+        if self.round {
+            Some(count.round())
+        } else {
+            Some(count)
+        }
+    }
+}
+
+// A single row (gene or metacount) of a counts matrix. Any annotation columns
+// (such as the featureCounts Chr, Start, End, Strand & Length) are carried through unchanged:
+pub struct Record {
+    pub gene: String,
+    pub annotation: Vec<String>,
+    pub counts: Vec<f64>,
+}
+
+impl Record {
+    // Format the row for output:
+    pub fn format(&self) -> String {
+        self.format_decimals(None)
+    }
+
+    // Format the row for output, writing the values to a fixed number of decimal places if given:
+    pub fn format_decimals(&self, decimals: Option<usize>) -> String {
+        let mut row = vec![self.gene.clone()];
+        row.extend(self.annotation.iter().cloned());
+        row.push(match decimals {
+            Some(d) => self
+                .counts
+                .iter()
+                .map(|c| format!("{:.*}", d, c))
+                .collect::<Vec<String>>()
+                .join("\t"),
+            None => format_counts(&self.counts),
+        });
+        row.join("\t")
+    }
+}
+
+// A counts matrix header, along with an iterator over its rows:
+pub struct CountsReader {
+    pub id_column: String,
+    pub annotation_columns: Vec<String>,
+    pub samples: Vec<String>,
+    pub records: Box<dyn Iterator<Item = Result<Record, Error>>>,
+}
+
+impl CountsReader {
+    // Format the matrix header line:
+    pub fn header(&self) -> String {
+        let mut header = vec![self.id_column.as_str()];
+        header.extend(self.annotation_columns.iter().map(|s| s.as_str()));
+        header.extend(self.samples.iter().map(|s| s.as_str()));
+        header.join("\t")
+    }
+}
+
+// The number of significant digits written for non-integer values, so that floating point noise
+// from summing fractional counts (such as 12.745000000000001) is not written:
+const SIGNIFICANT_DIGITS: usize = 12;
+
+// Format a value for output, rounding non-integers to a bounded number of significant digits:
+pub fn format_value(value: f64) -> String {
+    if !value.is_finite() || value.fract() == 0.0 {
+        return value.to_string();
+    }
+    match format!("{:.*e}", SIGNIFICANT_DIGITS - 1, value).parse::<f64>() {
+        Ok(v) => v.to_string(),
+        Err(_) => value.to_string(),
+    }
+}
+
+// Format a row of counts for output:
+pub fn format_counts(counts: &[f64]) -> String {
+    counts
+        .iter()
+        .map(|c| format_value(*c))
+        .collect::<Vec<String>>()
+        .join("\t")
+}
+
+// An iterator over the rows of a tab-separated counts matrix:
+pub struct MatrixRecords {
+    pub lines: Lines<Box<dyn BufRead>>,
+    pub n_annotation: usize,
+    pub n_samples: usize,
+    pub mode: CountMode,
+}
+
+impl Iterator for MatrixRecords {
+    type Item = Result<Record, Error>;
+    fn next(&mut self) -> Option<Self::Item> {
+        let mode = self.mode;
+        for line_res in &mut self.lines {
+            let line = match line_res {
+                Ok(l) => l,
+                Err(_) => {
+                    return Some(Err(Error::new(
+                        ErrorKind::InvalidData,
+                        "failed to parse input file",
+                    )))
+                }
+            };
+            let line_trimmed = line.trim();
+            let line_data: Vec<_> = line_trimmed.split('\t').collect();
+
+            // Extract the counts:
+            let counts: Vec<_> = match line_data
+                .iter()
+                .skip(1 + self.n_annotation)
+                .map(|s| mode.parse(s))
+                .collect()
+            {
+                Some(c) => c,
+                None => {
+                    warn!(
+                        "{}",
+                        format!("failed to convert counts from line {}", line_trimmed)
+                    );
+                    continue;
+                }
+            };
+            if line_data.len() != 1 + self.n_annotation + self.n_samples {
+                warn!(
+                    "{}",
+                    format!("incorrect number of counts in line {}", line_trimmed)
+                );
+                continue;
+            }
+            return Some(Ok(Record {
+                gene: String::from(line_data[0]),
+                annotation: line_data[1..=self.n_annotation]
+                    .iter()
+                    .map(|s| String::from(*s))
+                    .collect(),
+                counts,
+            }));
+        }
+        None
+    }
+}
+
+// Read a tab-separated counts matrix with a header row:
+pub fn read_matrix(input: Box<dyn BufRead>, mode: CountMode) -> Result<CountsReader, Error> {
+    let mut lines = input.lines();
+
+    // Read the file header:
+    let file_header = match lines.next() {
+        Some(Ok(h)) => Ok(h),
+        _ => Err(Error::new(
+            ErrorKind::UnexpectedEof,
+            "failed to read input file header",
+        )),
+    }?;
+    let mut header_data = file_header.trim().split('\t').map(String::from);
+    let id_column = header_data.next().unwrap_or_default();
+    let samples: Vec<String> = header_data.collect();
+
+    Ok(CountsReader {
+        id_column,
+        annotation_columns: Vec::new(),
+        records: Box::new(MatrixRecords {
+            lines,
+            n_annotation: 0,
+            n_samples: samples.len(),
+            mode,
+        }),
+        samples,
+    })
+}
@@ -0,0 +1,138 @@
+use crate::Sample;
+use log::*;
+
+// The metacounts holding the reads not assigned to any feature (for HTSeq, STAR & featureCounts):
+const NO_FEATURE_METACOUNTS: &[&str] =
+    &["__no_feature", "__N_noFeature", "__Unassigned_NoFeatures"];
+
+// The metacounts holding the reads assigned to more than one feature:
+const AMBIGUOUS_METACOUNTS: &[&str] = &["__ambiguous", "__N_ambiguous", "__Unassigned_Ambiguity"];
+
+// The QC checks that can reject a sample:
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SampleQcReason {
+    MinLibrarySize,
+    MinDetectedGenes,
+    MaxNoFeatureFraction,
+    MaxAmbiguousFraction,
+}
+
+impl SampleQcReason {
+    // The name used when reporting the reason:
+    pub fn name(self) -> &'static str {
+        match self {
+            SampleQcReason::MinLibrarySize => "min_library_size",
+            SampleQcReason::MinDetectedGenes => "min_detected_genes",
+            SampleQcReason::MaxNoFeatureFraction => "max_no_feature_fraction",
+            SampleQcReason::MaxAmbiguousFraction => "max_ambiguous_fraction",
+        }
+    }
+}
+
+// Format a set of sample QC reasons for output:
+pub fn format_reasons(reasons: &[SampleQcReason]) -> String {
+    reasons
+        .iter()
+        .map(|r| r.name())
+        .collect::<Vec<&str>>()
+        .join(",")
+}
+
+// The per-sample QC filters:
+pub struct SampleQc {
+    pub min_library_size: Option<f64>,
+    pub min_detected_genes: Option<u64>,
+    pub max_no_feature_fraction: Option<f64>,
+    pub max_ambiguous_fraction: Option<f64>,
+}
+
+impl SampleQc {
+    // Whether any sample QC filters are given:
+    pub fn is_active(&self) -> bool {
+        self.min_library_size.is_some()
+            || self.min_detected_genes.is_some()
+            || self.max_no_feature_fraction.is_some()
+            || self.max_ambiguous_fraction.is_some()
+    }
+
+    // Find the fraction of a sample's reads held in the first of a set of metacounts present.
+    // The total reads are as Sample::total_reads:
+    fn metacount_fraction(
+        sample: &Sample,
+        metacount_names: &[String],
+        names: &[&str],
+    ) -> Option<f64> {
+        let i = metacount_names
+            .iter()
+            .position(|m| names.contains(&m.as_str()))?;
+        Some(sample.metacounts[i] / sample.total_reads(metacount_names))
+    }
+
+    // Warn about any metacount fraction filters that cannot be applied as the metacount is absent:
+    pub fn check_metacounts(&self, metacount_names: &[String]) {
+        let fractions = [
+            (self.max_no_feature_fraction, NO_FEATURE_METACOUNTS),
+            (self.max_ambiguous_fraction, AMBIGUOUS_METACOUNTS),
+        ];
+        for (_, names) in fractions.iter().filter(|(m, _)| m.is_some()) {
+            if !metacount_names.iter().any(|m| names.contains(&m.as_str())) {
+                warn!(
+                    "{}",
+                    format!(
+                        "no {} metacount found (ignoring its fraction filter)",
+                        names[0].trim_start_matches('_')
+                    )
+                );
+            }
+        }
+    }
+
+    // Test a single sample (with the given library size), returning the reasons for any failures.
+    // The metacount fraction filters are skipped if the metacount is absent:
+    pub fn test(
+        &self,
+        sample: &Sample,
+        lib_size: f64,
+        metacount_names: &[String],
+    ) -> Vec<SampleQcReason> {
+        let mut reasons = Vec::new();
+        if self.min_library_size.is_some_and(|m| lib_size < m) {
+            reasons.push(SampleQcReason::MinLibrarySize);
+        }
+        if self
+            .min_detected_genes
+            .is_some_and(|m| sample.total_expressed < m)
+        {
+            reasons.push(SampleQcReason::MinDetectedGenes);
+        }
+        let fractions = [
+            (
+                self.max_no_feature_fraction,
+                NO_FEATURE_METACOUNTS,
+                SampleQcReason::MaxNoFeatureFraction,
+            ),
+            (
+                self.max_ambiguous_fraction,
+                AMBIGUOUS_METACOUNTS,
+                SampleQcReason::MaxAmbiguousFraction,
+            ),
+        ];
+        for (max, names, reason) in fractions.iter() {
+            let max = match max {
+                Some(m) => *m,
+                None => continue,
+            };
+            if SampleQc::metacount_fraction(sample, metacount_names, names).is_some_and(|f| f > max)
+            {
+                reasons.push(*reason);
+            }
+        }
+        reasons
+    }
+}
+
+// Keep only the values of the retained samples:
+pub fn retain_samples<T>(values: &mut Vec<T>, keep: &[bool]) {
+    let mut keep = keep.iter();
+    values.retain(|_| *keep.next().unwrap_or(&true));
+}
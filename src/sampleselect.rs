@@ -0,0 +1,137 @@
+use crate::input::{expand_path, open_input};
+use log::*;
+use std::collections::{HashMap, HashSet};
+use std::io::prelude::*;
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+
+// Read the non-empty, non-comment lines of a file, splitting them into tab-separated fields:
+fn read_rows(filename: &str) -> Result<Vec<Vec<String>>, Error> {
+    let mut rows = Vec::new();
+    for line in open_input(filename)?.lines() {
+        let line = line?;
+        let line_trimmed = line.trim();
+        if line_trimmed.is_empty() || line_trimmed.starts_with('#') {
+            continue;
+        }
+        rows.push(line_trimmed.split('\t').map(String::from).collect());
+    }
+    Ok(rows)
+}
+
+// Parse a list of sample names, given either as a file (with one name per line) or as a
+// comma-separated list:
+pub fn parse_sample_list(value: &str) -> Result<Vec<String>, Error> {
+    if let Some(filename) = expand_path(Path::new(value)).filter(|f| Path::new(f).is_file()) {
+        info!("{}", format!("reading sample names from {}", filename));
+        return Ok(read_rows(&filename)?
+            .into_iter()
+            .map(|r| r[0].clone())
+            .collect());
+    }
+    Ok(value
+        .split(',')
+        .filter(|s| !s.is_empty())
+        .map(String::from)
+        .collect())
+}
+
+// Read a tab-separated file mapping old sample names (first column) to new ones (second column):
+pub fn read_rename(path: &Path) -> Result<HashMap<String, String>, Error> {
+    let filename = match expand_path(path) {
+        Some(f) => f,
+        None => return Err(Error::new(ErrorKind::NotFound, "rename file not found")),
+    };
+    info!("{}", format!("reading sample renaming from {}", filename));
+    let mut rename = HashMap::new();
+    for row in read_rows(&filename)? {
+        if row.len() < 2 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("no new name given for sample {} in rename file", row[0]),
+            ));
+        }
+        rename.insert(row[0].clone(), row[1].clone());
+    }
+    Ok(rename)
+}
+
+// Find the (indices of the) samples to keep, in output order. If a selection is given, only the
+// selected samples are kept (in the selection's order); any dropped samples are then removed:
+pub fn select_samples(
+    names: &[String],
+    selection: Option<&[String]>,
+    dropped: &[String],
+) -> Result<Vec<usize>, Error> {
+    let mut indices: Vec<usize> = match selection {
+        Some(selection) => {
+            let mut seen = HashSet::new();
+            let mut indices = Vec::with_capacity(selection.len());
+            for name in selection {
+                if !seen.insert(name) {
+                    return Err(Error::new(
+                        ErrorKind::InvalidInput,
+                        format!("sample {} is selected twice", name),
+                    ));
+                }
+                match names.iter().position(|n| n == name) {
+                    Some(i) => indices.push(i),
+                    None => {
+                        return Err(Error::new(
+                            ErrorKind::InvalidInput,
+                            format!("selected sample {} is not in the counts matrix", name),
+                        ))
+                    }
+                }
+            }
+            indices
+        }
+        None => (0..names.len()).collect(),
+    };
+    for name in dropped {
+        if !names.contains(name) {
+            warn!(
+                "{}",
+                format!("dropped sample {} is not in the counts matrix", name)
+            );
+        }
+    }
+    indices.retain(|i| !dropped.contains(&names[*i]));
+    if indices.is_empty() {
+        return Err(Error::new(ErrorKind::InvalidInput, "no samples selected"));
+    }
+    Ok(indices)
+}
+
+// Take the values at the given (unique) indices, in order:
+pub fn reorder<T>(values: Vec<T>, indices: &[usize]) -> Vec<T> {
+    let mut values: Vec<Option<T>> = values.into_iter().map(Some).collect();
+    indices.iter().filter_map(|i| values[*i].take()).collect()
+}
+
+// Rename a set of samples, checking that the new names are unique:
+pub fn rename_samples(names: &mut [String], rename: &HashMap<String, String>) -> Result<(), Error> {
+    for old in rename.keys() {
+        if !names.contains(old) {
+            warn!(
+                "{}",
+                format!("renamed sample {} is not in the counts matrix", old)
+            );
+        }
+    }
+    for name in names.iter_mut() {
+        if let Some(new) = rename.get(name) {
+            *name = new.clone();
+        }
+    }
+    let mut seen = HashSet::new();
+    for name in names.iter() {
+        if !seen.insert(name) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("duplicate sample name {} after renaming", name),
+            ));
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,90 @@
+use crate::input::{expand_path, open_input};
+use log::*;
+use std::collections::HashMap;
+use std::io::prelude::*;
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+
+// A sample sheet mapping sample names to their groups:
+pub struct SampleSheet {
+    groups: HashMap<String, String>,
+}
+
+impl SampleSheet {
+    // Read a tab-separated sample sheet with the sample name in the first column and its group
+    // in the second. A header row (starting with "sample") and comment lines are skipped:
+    pub fn read(path: &Path) -> Result<SampleSheet, Error> {
+        let filename = match expand_path(path) {
+            Some(f) => f,
+            None => return Err(Error::new(ErrorKind::NotFound, "sample sheet not found")),
+        };
+        info!("{}", format!("reading sample sheet from {}", filename));
+        let mut groups = HashMap::new();
+        for (i, line) in open_input(&filename)?.lines().enumerate() {
+            let line = line?;
+            let line_trimmed = line.trim();
+            if line_trimmed.is_empty() || line_trimmed.starts_with('#') {
+                continue;
+            }
+            let line_data: Vec<_> = line_trimmed.split('\t').collect();
+            if i == 0 && line_data[0].eq_ignore_ascii_case("sample") {
+                continue;
+            }
+            if line_data.len() < 2 {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("no group given for sample {} in sample sheet", line_data[0]),
+                ));
+            }
+            if groups
+                .insert(String::from(line_data[0]), String::from(line_data[1]))
+                .is_some()
+            {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("sample {} is listed twice in sample sheet", line_data[0]),
+                ));
+            }
+        }
+        Ok(SampleSheet { groups })
+    }
+
+    // Look up the group of each of a set of samples:
+    pub fn groups(&self, samples: &[String]) -> Result<Vec<String>, Error> {
+        for name in self.groups.keys() {
+            if !samples.contains(name) {
+                warn!(
+                    "{}",
+                    format!("sample sheet sample {} is not in the counts matrix", name)
+                );
+            }
+        }
+        samples
+            .iter()
+            .map(|s| match self.groups.get(s) {
+                Some(g) => Ok(g.clone()),
+                None => Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("sample {} is not in the sample sheet", s),
+                )),
+            })
+            .collect()
+    }
+}
+
+// Index a set of per-sample groups, returning the group names (in order of first appearance)
+// and the index of each sample's group:
+pub fn index_groups(groups: &[String]) -> (Vec<String>, Vec<usize>) {
+    let mut names: Vec<String> = Vec::new();
+    let mut indices = Vec::with_capacity(groups.len());
+    for g in groups.iter() {
+        match names.iter().position(|name| name == g) {
+            Some(i) => indices.push(i),
+            None => {
+                indices.push(names.len());
+                names.push(g.clone());
+            }
+        }
+    }
+    (names, indices)
+}
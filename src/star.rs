@@ -0,0 +1,131 @@
+use crate::input::open_input;
+use crate::merge::{merge_samples, SampleFile};
+use crate::reader::{CountMode, CountsReader};
+use log::*;
+use std::io::prelude::*;
+use std::io::{Error, ErrorKind};
+use std::str::FromStr;
+
+// The STAR row recording reads that overlapped no feature:
+const NO_FEATURE: &str = "N_noFeature";
+
+// The forward & reverse noFeature ratio below which a library is inferred to be stranded:
+const STRANDED_RATIO: f64 = 0.5;
+
+// The STAR ReadsPerGene count columns:
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StarStrand {
+    Auto,
+    Unstranded,
+    Forward,
+    Reverse,
+}
+
+impl StarStrand {
+    pub const NAMES: &'static [&'static str] = &["auto", "unstranded", "forward", "reverse"];
+
+    // The (zero-based) column holding the counts for this strandedness:
+    fn column(self) -> usize {
+        match self {
+            StarStrand::Auto | StarStrand::Unstranded => 1,
+            StarStrand::Forward => 2,
+            StarStrand::Reverse => 3,
+        }
+    }
+}
+
+impl FromStr for StarStrand {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(StarStrand::Auto),
+            "unstranded" => Ok(StarStrand::Unstranded),
+            "forward" => Ok(StarStrand::Forward),
+            "reverse" => Ok(StarStrand::Reverse),
+            _ => Err(format!("unknown strandedness {}", s)),
+        }
+    }
+}
+
+// Read the N_noFeature counts (unstranded, forward & reverse) from a ReadsPerGene file:
+fn read_no_feature(filename: &str) -> Result<[u64; 3], Error> {
+    for line in open_input(filename)?.lines() {
+        let line = line?;
+        let line_data: Vec<_> = line.trim().split('\t').collect();
+        if line_data[0] != NO_FEATURE {
+            continue;
+        }
+        let counts: Vec<u64> = match line_data.iter().skip(1).map(|v| v.parse()).collect() {
+            Ok(c) => c,
+            Err(_) => break,
+        };
+        if counts.len() == 3 {
+            return Ok([counts[0], counts[1], counts[2]]);
+        }
+        break;
+    }
+    Err(Error::new(
+        ErrorKind::InvalidData,
+        format!("failed to read {} counts from {}", NO_FEATURE, filename),
+    ))
+}
+
+// Infer the strandedness of a set of samples. The stranded column with the fewest noFeature
+// reads is chosen if it has markedly fewer than the other; otherwise the library is unstranded:
+fn infer_strand(files: &[SampleFile]) -> Result<StarStrand, Error> {
+    let mut totals = [0u64; 3];
+    for f in files.iter() {
+        for (t, c) in totals.iter_mut().zip(read_no_feature(&f.filename)?.iter()) {
+            *t += c;
+        }
+    }
+    let (forward, reverse) = (totals[1] as f64, totals[2] as f64);
+    debug!(
+        "{}",
+        format!(
+            "{} counts: forward {}, reverse {}",
+            NO_FEATURE, forward, reverse
+        )
+    );
+    if forward.min(reverse) >= STRANDED_RATIO * forward.max(reverse) {
+        Ok(StarStrand::Unstranded)
+    } else if forward < reverse {
+        Ok(StarStrand::Forward)
+    } else {
+        Ok(StarStrand::Reverse)
+    }
+}
+
+// Read a set of STAR ReadsPerGene.out.tab files as a single counts matrix. The N_ rows
+// are renamed with a double underscore prefix so that they are treated as metacounts:
+pub fn read_star(
+    files: &[SampleFile],
+    strand: StarStrand,
+    mode: CountMode,
+) -> Result<CountsReader, Error> {
+    let strand = match strand {
+        StarStrand::Auto => {
+            let inferred = infer_strand(files)?;
+            info!("{}", format!("inferred STAR strandedness {:?}", inferred));
+            inferred
+        }
+        s => s,
+    };
+    let column = strand.column();
+    let parse = move |line: &str| {
+        let line_data: Vec<_> = line.split('\t').collect();
+        let count = mode.parse(line_data.get(column)?)?;
+        let gene = if line_data[0].starts_with("N_") {
+            format!("__{}", line_data[0])
+        } else {
+            String::from(line_data[0])
+        };
+        Some((gene, count))
+    };
+    Ok(CountsReader {
+        id_column: String::from("gene"),
+        annotation_columns: Vec::new(),
+        samples: files.iter().map(|f| f.name.clone()).collect(),
+        records: merge_samples(files, 0, Box::new(parse))?,
+    })
+}
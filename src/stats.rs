@@ -0,0 +1,113 @@
+// The mean of a set of values:
+pub fn mean(values: &[f64]) -> f64 {
+    values.iter().sum::<f64>() / values.len() as f64
+}
+
+// The mean of a set of values after removing the given proportion from each end (as R's mean
+// with trim):
+pub fn trimmed_mean(values: &[f64], trim: f64) -> f64 {
+    let mut sorted = values.to_vec();
+    sorted.sort_by(|a, b| a.total_cmp(b));
+    let n_trim = (sorted.len() as f64 * trim).floor() as usize;
+    mean(&sorted[n_trim..sorted.len() - n_trim])
+}
+
+// The median of a set of values:
+pub fn median(values: &[f64]) -> f64 {
+    let mut sorted = values.to_vec();
+    sorted.sort_by(|a, b| a.total_cmp(b));
+    let n = sorted.len();
+    if n == 0 {
+        f64::NAN
+    } else if n % 2 == 1 {
+        sorted[n / 2]
+    } else {
+        (sorted[n / 2 - 1] + sorted[n / 2]) / 2.0
+    }
+}
+
+// The sample variance (with an n - 1 denominator) of a set of values:
+pub fn variance(values: &[f64]) -> f64 {
+    let m = mean(values);
+    values.iter().map(|v| (v - m).powi(2)).sum::<f64>() / (values.len() as f64 - 1.0)
+}
+
+// The p-quantile of a set of values (interpolating as R's default type 7 quantile):
+pub fn quantile(values: &[f64], p: f64) -> f64 {
+    let mut sorted = values.to_vec();
+    sorted.sort_by(|a, b| a.total_cmp(b));
+    let n = sorted.len();
+    if n == 0 {
+        return f64::NAN;
+    }
+    let h = (n - 1) as f64 * p;
+    let lo = h.floor() as usize;
+    match sorted.get(lo + 1) {
+        Some(hi) => sorted[lo] + (h - lo as f64) * (hi - sorted[lo]),
+        None => sorted[lo],
+    }
+}
+
+// The ranks of a set of values, with ties given their average rank (as R's rank):
+pub fn rank(values: &[f64]) -> Vec<f64> {
+    let mut order: Vec<usize> = (0..values.len()).collect();
+    order.sort_by(|a, b| values[*a].total_cmp(&values[*b]));
+    let mut ranks = vec![0.0; values.len()];
+    let mut start = 0;
+    while start < order.len() {
+        let mut end = start + 1;
+        while end < order.len() && values[order[end]] == values[order[start]] {
+            end += 1;
+        }
+        let r = (start + end + 1) as f64 / 2.0;
+        for i in order[start..end].iter() {
+            ranks[*i] = r;
+        }
+        start = end;
+    }
+    ranks
+}
+
+// The counts per million of a count, given its sample's library size:
+pub fn cpm(count: f64, lib_size: f64) -> f64 {
+    count / lib_size * 1e6
+}
+
+// Format a statistic for output, writing undefined values as NA:
+pub fn format_stat(value: f64) -> String {
+    if value.is_finite() {
+        value.to_string()
+    } else {
+        String::from("NA")
+    }
+}
+
+// The gene statistics table column names (following the gene ID):
+pub const GENE_STATS_COLUMNS: &[&str] = &[
+    "total",
+    "mean",
+    "median",
+    "variance",
+    "cv",
+    "zeros",
+    "expressed",
+    "status",
+];
+
+// Format a row of the gene statistics table:
+pub fn format_gene_stats(gene: &str, counts: &[f64], n_expressed: u64, passed: bool) -> String {
+    let m = mean(counts);
+    let v = variance(counts);
+    [
+        String::from(gene),
+        format_stat(counts.iter().sum()),
+        format_stat(m),
+        format_stat(median(counts)),
+        format_stat(v),
+        format_stat(v.sqrt() / m),
+        counts.iter().filter(|c| **c == 0.0).count().to_string(),
+        n_expressed.to_string(),
+        String::from(if passed { "pass" } else { "fail" }),
+    ]
+    .join("\t")
+}
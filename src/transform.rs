@@ -0,0 +1,183 @@
+use crate::normalise::{self, Normalisation};
+use crate::stats;
+use log::*;
+use std::io::{Error, ErrorKind};
+use std::str::FromStr;
+
+// The minimum dispersion estimate used when fitting the dispersion trend (as DESeq2):
+const MIN_FIT_DISPERSION: f64 = 1e-6;
+
+// The maximum number of iterations when fitting the dispersion trend:
+const MAX_FIT_ITERATIONS: usize = 10;
+
+// The maximum number of iterations of the gamma GLM fit (as R's glm):
+const MAX_GLM_ITERATIONS: usize = 25;
+
+// The minimum dispersion estimate & trimming proportion used for the mean dispersion fallback
+// (as DESeq2's mean fit):
+const MIN_MEAN_DISPERSION: f64 = 1e-7;
+const MEAN_DISPERSION_TRIM: f64 = 0.001;
+
+// The supported transforms of the output values:
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Transform {
+    Log2,
+    Log2Cpm,
+    Asinh,
+    Vst,
+}
+
+impl Transform {
+    pub const NAMES: &'static [&'static str] = &["log2", "log2cpm", "asinh", "vst"];
+}
+
+impl FromStr for Transform {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "log2" => Ok(Transform::Log2),
+            "log2cpm" => Ok(Transform::Log2Cpm),
+            "asinh" => Ok(Transform::Asinh),
+            "vst" => Ok(Transform::Vst),
+            _ => Err(format!("unknown transform {}", s)),
+        }
+    }
+}
+
+// Fit a dispersion-mean trend (dispersion = a0 + a1 / mean) to a set of per-gene dispersion
+// estimates by a gamma-family GLM with an identity link (fitted by iteratively reweighted least
+// squares), returning (a0, a1):
+fn fit_gamma_identity(dispersions: &[f64], inv_means: &[f64], start: (f64, f64)) -> (f64, f64) {
+    let mut coefs = start;
+    for _ in 0..MAX_GLM_ITERATIONS {
+        // Weighted least squares of the dispersions on the inverse means, weighted by 1 / fitted²:
+        let (mut sw, mut swx, mut swy, mut swxx, mut swxy) = (0.0, 0.0, 0.0, 0.0, 0.0);
+        for (y, x) in dispersions.iter().zip(inv_means.iter()) {
+            let mu = coefs.0 + coefs.1 * x;
+            let w = 1.0 / (mu * mu);
+            sw += w;
+            swx += w * x;
+            swy += w * y;
+            swxx += w * x * x;
+            swxy += w * x * y;
+        }
+        let a1 = (sw * swxy - swx * swy) / (sw * swxx - swx * swx);
+        let a0 = (swy - a1 * swx) / sw;
+        let converged =
+            (a0 - coefs.0).abs() <= 1e-10 * a0.abs() && (a1 - coefs.1).abs() <= 1e-10 * a1.abs();
+        coefs = (a0, a1);
+        if converged || !(a0 > 0.0 && a1 > 0.0) {
+            break;
+        }
+    }
+    coefs
+}
+
+// The fitted dispersion: either a parametric trend (with the asymptotic dispersion & extra-Poisson
+// coefficients), or a single mean dispersion:
+enum DispersionFit {
+    Parametric(f64, f64),
+    Mean(f64),
+}
+
+// A variance-stabilising transform for negative binomial counts, following DESeq2's vst with a
+// parametric dispersion fit (falling back to the mean dispersion if the fit fails, as DESeq2).
+// Dispersions are estimated by the method of moments on the median-of-ratios normalised counts:
+pub struct Vst {
+    size_factors: Vec<f64>,
+    fit: DispersionFit,
+}
+
+impl Vst {
+    // Fit the dispersion trend to a set of (passing) genes:
+    pub fn fit(genes: &[&[f64]], samples: &[String]) -> Result<Vst, Error> {
+        let size_factors =
+            normalise::size_factors(genes, samples, Normalisation::Mor)?.size_factors;
+        let mean_inv_sf = stats::mean(&size_factors.iter().map(|s| 1.0 / s).collect::<Vec<f64>>());
+        let mut estimates = Vec::with_capacity(genes.len());
+        for counts in genes.iter() {
+            let normalised: Vec<f64> = counts
+                .iter()
+                .zip(size_factors.iter())
+                .map(|(c, s)| c / s)
+                .collect();
+            let m = stats::mean(&normalised);
+            let d = (stats::variance(&normalised) - mean_inv_sf * m) / (m * m);
+            if m > 0.0 {
+                estimates.push((d, m));
+            }
+        }
+        let (dispersions, means): (Vec<f64>, Vec<f64>) = estimates
+            .iter()
+            .filter(|(d, _)| *d > MIN_FIT_DISPERSION)
+            .copied()
+            .unzip();
+
+        // Fit the trend, iteratively excluding genes far from the current fit:
+        let mut coefs = (0.1, 1.0);
+        for _ in 0..MAX_FIT_ITERATIONS {
+            let (good_dispersions, good_inv_means): (Vec<f64>, Vec<f64>) = dispersions
+                .iter()
+                .zip(means.iter())
+                .filter(|(d, m)| {
+                    let residual = *d / (coefs.0 + coefs.1 / *m);
+                    residual > 1e-4 && residual < 15.0
+                })
+                .map(|(d, m)| (*d, 1.0 / m))
+                .unzip();
+            if good_dispersions.len() < 2 {
+                break;
+            }
+            let old_coefs = coefs;
+            coefs = fit_gamma_identity(&good_dispersions, &good_inv_means, coefs);
+            if !(coefs.0 > 0.0 && coefs.1 > 0.0) {
+                break;
+            }
+            if (coefs.0 / old_coefs.0).ln().powi(2) + (coefs.1 / old_coefs.1).ln().powi(2) < 1e-6 {
+                return Ok(Vst {
+                    size_factors,
+                    fit: DispersionFit::Parametric(coefs.0, coefs.1),
+                });
+            }
+        }
+
+        // Fall back to the (trimmed) mean dispersion:
+        warn!("parametric dispersion fit failed, using the mean dispersion");
+        let usable: Vec<f64> = estimates
+            .iter()
+            .map(|(d, _)| *d)
+            .filter(|d| *d > MIN_MEAN_DISPERSION)
+            .collect();
+        if usable.is_empty() {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "no genes have a dispersion estimate for the variance-stabilising transform",
+            ));
+        }
+        Ok(Vst {
+            size_factors,
+            fit: DispersionFit::Mean(stats::trimmed_mean(&usable, MEAN_DISPERSION_TRIM)),
+        })
+    }
+
+    // Transform a gene's counts:
+    pub fn transform(&self, counts: &[f64]) -> Vec<f64> {
+        counts
+            .iter()
+            .zip(self.size_factors.iter())
+            .map(|(c, s)| {
+                let q = c / s;
+                match self.fit {
+                    DispersionFit::Parametric(a0, a1) => {
+                        ((1.0 + a1 + 2.0 * a0 * q + 2.0 * (a0 * q * (1.0 + a1 + a0 * q)).sqrt())
+                            / (4.0 * a0))
+                            .log2()
+                    }
+                    DispersionFit::Mean(alpha) => {
+                        (2.0 * (alpha * q).sqrt().asinh() - alpha.ln() - 4f64.ln()) / 2f64.ln()
+                    }
+                }
+            })
+            .collect()
+    }
+}
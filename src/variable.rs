@@ -0,0 +1,143 @@
+use crate::stats;
+use std::str::FromStr;
+
+// The number of mean expression bins used when calculating dispersion residuals:
+const DISPERSION_BINS: usize = 20;
+
+// The measures by which genes can be ranked for the top variable genes:
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Variability {
+    Variance,
+    LogCpmVariance,
+    Dispersion,
+}
+
+impl Variability {
+    pub const NAMES: &'static [&'static str] = &["variance", "log-cpm-variance", "dispersion"];
+}
+
+impl FromStr for Variability {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "variance" => Ok(Variability::Variance),
+            "log-cpm-variance" => Ok(Variability::LogCpmVariance),
+            "dispersion" => Ok(Variability::Dispersion),
+            _ => Err(format!("unknown variability measure {}", s)),
+        }
+    }
+}
+
+// Calculate the dispersion residuals: the log dispersion (variance / mean) of each gene's CPMs,
+// standardised against the genes with a similar mean. Genes are binned into equal-width bins of
+// log mean CPM, and the residual is the gene's distance from its bin's mean log dispersion in
+// units of the bin's standard deviation. Genes alone in their bin (or in a bin without any
+// spread) have a residual of zero:
+fn dispersion_residuals(cpms: &[Vec<f64>]) -> Vec<f64> {
+    let (log_means, log_dispersions): (Vec<f64>, Vec<f64>) = cpms
+        .iter()
+        .map(|c| {
+            let m = stats::mean(c);
+            (m.ln(), (stats::variance(c) / m).ln())
+        })
+        .unzip();
+
+    // Bin the genes by log mean:
+    let defined: Vec<bool> = log_means
+        .iter()
+        .zip(log_dispersions.iter())
+        .map(|(m, d)| m.is_finite() && d.is_finite())
+        .collect();
+    let min = log_means
+        .iter()
+        .zip(defined.iter())
+        .filter(|(_, d)| **d)
+        .map(|(m, _)| *m)
+        .fold(f64::INFINITY, f64::min);
+    let max = log_means
+        .iter()
+        .zip(defined.iter())
+        .filter(|(_, d)| **d)
+        .map(|(m, _)| *m)
+        .fold(f64::NEG_INFINITY, f64::max);
+    let width = (max - min) / DISPERSION_BINS as f64;
+    let bins: Vec<usize> = log_means
+        .iter()
+        .map(|m| {
+            if width > 0.0 {
+                (((m - min) / width) as usize).min(DISPERSION_BINS - 1)
+            } else {
+                0
+            }
+        })
+        .collect();
+
+    // Standardise the log dispersions within each bin:
+    let mut binned: Vec<Vec<f64>> = vec![Vec::new(); DISPERSION_BINS];
+    for i in (0..cpms.len()).filter(|i| defined[*i]) {
+        binned[bins[i]].push(log_dispersions[i]);
+    }
+    let bin_stats: Vec<(f64, f64)> = binned
+        .iter()
+        .map(|b| (stats::mean(b), stats::variance(b).sqrt()))
+        .collect();
+    (0..cpms.len())
+        .map(|i| {
+            if !defined[i] {
+                return f64::NAN;
+            }
+            let (m, sd) = bin_stats[bins[i]];
+            if sd.is_finite() && sd > 0.0 {
+                (log_dispersions[i] - m) / sd
+            } else {
+                0.0
+            }
+        })
+        .collect()
+}
+
+// Score a set of genes by the given variability measure:
+pub fn scores(
+    genes: &[&[f64]],
+    lib_sizes: &[f64],
+    measure: Variability,
+    pseudocount: f64,
+) -> Vec<f64> {
+    let to_cpm = |counts: &[f64]| -> Vec<f64> {
+        counts
+            .iter()
+            .zip(lib_sizes.iter())
+            .map(|(v, l)| stats::cpm(*v, *l))
+            .collect()
+    };
+    match measure {
+        Variability::Variance => genes.iter().map(|c| stats::variance(c)).collect(),
+        Variability::LogCpmVariance => genes
+            .iter()
+            .map(|c| {
+                let log_cpm: Vec<f64> =
+                    to_cpm(c).iter().map(|v| (v + pseudocount).log2()).collect();
+                stats::variance(&log_cpm)
+            })
+            .collect(),
+        Variability::Dispersion => {
+            let cpms: Vec<Vec<f64>> = genes.iter().map(|c| to_cpm(c)).collect();
+            dispersion_residuals(&cpms)
+        }
+    }
+}
+
+// Select the n highest-scoring genes, returning whether each gene was selected. Ties are broken
+// by input order, and undefined scores rank last:
+pub fn top_n(scores: &[f64], n: usize) -> Vec<bool> {
+    let mut order: Vec<usize> = (0..scores.len()).collect();
+    order.sort_by(|a, b| match (scores[*a].is_nan(), scores[*b].is_nan()) {
+        (false, false) => scores[*b].partial_cmp(&scores[*a]).unwrap(),
+        (a_nan, b_nan) => a_nan.cmp(&b_nan),
+    });
+    let mut selected = vec![false; scores.len()];
+    for i in order.into_iter().take(n) {
+        selected[i] = true;
+    }
+    selected
+}
@@ -0,0 +1,86 @@
+use std::process::Command;
+
+const DATA_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/data");
+
+// Run filter-counts with the given arguments, returning its (successful) output lines:
+fn run(args: &[&str]) -> Vec<String> {
+    let output = Command::new(env!("CARGO_BIN_EXE_filter-counts"))
+        .args(args)
+        .output()
+        .expect("failed to run filter-counts");
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .map(String::from)
+        .collect()
+}
+
+// Check that every output line has as many fields as the header:
+fn assert_rectangular(lines: &[String]) {
+    let n_fields = lines[0].split('\t').count();
+    for line in lines {
+        assert_eq!(line.split('\t').count(), n_fields, "{}", line);
+    }
+}
+
+#[test]
+fn featurecounts_stdout_metacounts_are_rectangular() {
+    let lines = run(&[
+        "-f",
+        "featurecounts",
+        "-s",
+        &format!("{}/featurecounts.txt", DATA_DIR),
+    ]);
+    assert!(lines.iter().any(|l| l.starts_with("__Assigned\tNA\t")));
+    assert_rectangular(&lines);
+}
+
+// The IDs of the genes in an output (skipping the header & metacounts):
+fn genes(lines: &[String]) -> Vec<&str> {
+    lines[1..]
+        .iter()
+        .map(|l| l.split('\t').next().unwrap())
+        .filter(|g| !g.starts_with("__"))
+        .collect()
+}
+
+#[test]
+fn annotation_list_options_do_not_take_the_input_path() {
+    let gtf = format!("{}/annotation.gtf", DATA_DIR);
+    let counts = format!("{}/annotation_counts.tsv", DATA_DIR);
+    let lines = run(&["--gtf", &gtf, "--exclude-chrom", "chrM", &counts]);
+    assert_eq!(genes(&lines), ["g1", "g3"]);
+    let lines = run(&[
+        "--gtf",
+        &gtf,
+        "--keep-biotype",
+        "protein_coding,lncRNA",
+        &counts,
+    ]);
+    assert_eq!(genes(&lines), ["g1", "g3"]);
+}
+
+#[test]
+fn annotated_stdout_metacounts_are_rectangular() {
+    let lines = run(&[
+        "--gtf",
+        &format!("{}/annotation.gtf", DATA_DIR),
+        "--annotate",
+        &format!("{}/annotation_counts.tsv", DATA_DIR),
+    ]);
+    assert_eq!(lines[0], "gene\tgene_name\tgene_biotype\tA\tB");
+    assert!(lines.contains(&String::from("__no_feature\tNA\tNA\t4\t6")));
+    assert_rectangular(&lines);
+}
+
+#[test]
+fn summed_fractional_counts_are_written_without_rounding_noise() {
+    let counts = format!("{}/fractional_counts.tsv", DATA_DIR);
+    let lines = run(&["--fractional", "--duplicates", "sum", &counts]);
+    assert_eq!(lines[1], "g1\t12.745\t3.3");
+}
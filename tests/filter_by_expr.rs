@@ -0,0 +1,49 @@
+use std::fs;
+use std::process::Command;
+
+const DATA_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/data");
+
+// Run filter-counts on the reference counts, returning the IDs of the passing genes:
+fn passing_genes(args: &[&str]) -> Vec<String> {
+    let output = Command::new(env!("CARGO_BIN_EXE_filter-counts"))
+        .args(args)
+        .arg(format!("{}/filter_by_expr_counts.tsv", DATA_DIR))
+        .output()
+        .expect("failed to run filter-counts");
+    assert!(output.status.success());
+    String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .skip(1)
+        .map(|l| String::from(l.split('\t').next().unwrap()))
+        .collect()
+}
+
+// Read the IDs of the genes kept in an edgeR reference output:
+fn reference_genes(name: &str) -> Vec<String> {
+    fs::read_to_string(format!("{}/{}", DATA_DIR, name))
+        .unwrap()
+        .lines()
+        .skip(1)
+        .map(|l| l.split('\t').collect::<Vec<_>>())
+        .filter(|l| l[1] == "TRUE")
+        .map(|l| String::from(l[0]))
+        .collect()
+}
+
+#[test]
+fn filter_by_expr_with_groups_matches_edger() {
+    let sample_sheet = format!("{}/filter_by_expr_samples.tsv", DATA_DIR);
+    assert_eq!(
+        passing_genes(&["--filter-by-expr", "--sample-sheet", &sample_sheet]),
+        reference_genes("filter_by_expr_group.tsv")
+    );
+}
+
+#[test]
+fn filter_by_expr_without_groups_matches_edger() {
+    assert_eq!(
+        passing_genes(&["--filter-by-expr"]),
+        reference_genes("filter_by_expr_nogroup.tsv")
+    );
+}
@@ -0,0 +1,80 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+const FEATURES: &str =
+    "g1\tG1\tGene Expression\ng2\tG2\tGene Expression\ng3\tG3\tGene Expression\n";
+const BARCODES: &str = "AAAC-1\nAAAG-1\n";
+
+// Write a Matrix Market directory (with the given matrix entries) to a fresh temporary directory:
+fn write_matrix(name: &str, size: &str, entries: &[&str]) -> PathBuf {
+    let dir = env::temp_dir().join(format!("filter-counts-mtx-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(dir.join("input")).unwrap();
+    let mut matrix = String::from("%%MatrixMarket matrix coordinate integer general\n%\n");
+    matrix.push_str(size);
+    matrix.push('\n');
+    for entry in entries {
+        matrix.push_str(entry);
+        matrix.push('\n');
+    }
+    fs::write(dir.join("input/matrix.mtx"), matrix).unwrap();
+    fs::write(dir.join("input/features.tsv"), FEATURES).unwrap();
+    fs::write(dir.join("input/barcodes.tsv"), BARCODES).unwrap();
+    dir
+}
+
+// Run filter-counts on a Matrix Market directory, writing to its output directory:
+fn filter(dir: &Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_filter-counts"))
+        .args(["-f", "mtx", "--output"])
+        .arg(dir.join("output"))
+        .args(args)
+        .arg(dir.join("input"))
+        .output()
+        .expect("failed to run filter-counts")
+}
+
+#[test]
+fn filters_genes_and_rewrites_the_size_line() {
+    let dir = write_matrix("filter", "3 2 4", &["1 1 5", "1 2 3", "2 1 1", "3 2 10"]);
+    let removed = dir.join("removed.tsv");
+    let output = filter(&dir, &["-m", "5", "--removed", removed.to_str().unwrap()]);
+    assert!(output.status.success());
+    let matrix = fs::read_to_string(dir.join("output/matrix.mtx")).unwrap();
+    let lines: Vec<&str> = matrix.lines().collect();
+    assert_eq!(lines[1], "2 2 3");
+    assert_eq!(&lines[2..], &["1 1 5", "1 2 3", "2 2 10"]);
+    let features = fs::read_to_string(dir.join("output/features.tsv")).unwrap();
+    assert_eq!(
+        features
+            .lines()
+            .map(|l| l.split('\t').next().unwrap())
+            .collect::<Vec<_>>(),
+        ["g1", "g3"]
+    );
+    assert_eq!(
+        fs::read_to_string(&removed).unwrap(),
+        "gene\tgene_name\tfeature_type\treason\ng2\tG2\tGene Expression\tmin_count\n"
+    );
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn duplicate_entries_are_an_error() {
+    let dir = write_matrix("duplicate", "3 2 3", &["1 1 5", "1 1 3", "1 1 1"]);
+    let output = filter(&dir, &[]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("duplicate Matrix Market entries"));
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn non_adjacent_duplicate_entries_are_an_error() {
+    let dir = write_matrix("scattered", "3 2 4", &["1 1 5", "2 1 1", "1 1 3", "1 1 2"]);
+    let output = filter(&dir, &[]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("duplicate Matrix Market entries"));
+    fs::remove_dir_all(&dir).unwrap();
+}
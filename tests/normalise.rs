@@ -0,0 +1,106 @@
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+const DATA_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/data");
+
+// Read a tab-separated table (with a header) into rows of fields:
+fn read_table(contents: &str) -> Vec<Vec<String>> {
+    contents
+        .lines()
+        .skip(1)
+        .map(|l| l.split('\t').map(String::from).collect())
+        .collect()
+}
+
+// Run filter-counts on the reference counts with the given normalisation method, returning the
+// normalised matrix & the size factor table:
+fn normalise(method: &str) -> (Vec<Vec<String>>, Vec<Vec<String>>) {
+    let size_factors = env::temp_dir().join(format!(
+        "filter-counts-size-factors-{}-{}.tsv",
+        method,
+        std::process::id()
+    ));
+    let output = Command::new(env!("CARGO_BIN_EXE_filter-counts"))
+        .args(["--normalise", method, "--size-factors"])
+        .arg(&size_factors)
+        .arg(format!("{}/normalise_counts.tsv", DATA_DIR))
+        .output()
+        .expect("failed to run filter-counts");
+    assert!(output.status.success());
+    let table = fs::read_to_string(&size_factors).unwrap();
+    fs::remove_file(&size_factors).unwrap();
+    (
+        read_table(&String::from_utf8(output.stdout).unwrap()),
+        read_table(&table),
+    )
+}
+
+// Read a column of the edgeR/DESeq2 reference factors:
+fn reference_factors(column: usize) -> Vec<f64> {
+    read_table(&fs::read_to_string(format!("{}/normalise_factors.tsv", DATA_DIR)).unwrap())
+        .iter()
+        .map(|r| r[column].parse().unwrap())
+        .collect()
+}
+
+// Check that two sets of values agree to a relative tolerance:
+fn assert_close(values: &[f64], expected: &[f64]) {
+    assert_eq!(values.len(), expected.len());
+    for (v, e) in values.iter().zip(expected.iter()) {
+        assert!((v - e).abs() <= 1e-10 * e.abs(), "{} != {}", v, e);
+    }
+}
+
+// Extract a column of the size factor table:
+fn factor_column(table: &[Vec<String>], column: usize) -> Vec<f64> {
+    table.iter().map(|r| r[column].parse().unwrap()).collect()
+}
+
+#[test]
+fn tmm_factors_match_edger() {
+    let (_, table) = normalise("tmm");
+    assert_close(&factor_column(&table, 2), &reference_factors(1));
+}
+
+#[test]
+fn upper_quartile_factors_match_edger() {
+    let (_, table) = normalise("uq");
+    assert_close(&factor_column(&table, 2), &reference_factors(2));
+}
+
+#[test]
+fn median_of_ratios_factors_match_deseq2() {
+    let (_, table) = normalise("mor");
+    assert_close(&factor_column(&table, 3), &reference_factors(3));
+}
+
+#[test]
+fn cpm_columns_sum_to_one_million() {
+    let (matrix, _) = normalise("cpm");
+    for j in 1..matrix[0].len() {
+        let total: f64 = matrix.iter().map(|r| r[j].parse::<f64>().unwrap()).sum();
+        assert!((total - 1e6).abs() < 1e-6);
+    }
+}
+
+#[test]
+fn zero_library_size_is_an_error() {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_filter-counts"))
+        .args(["--normalise", "tmm"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("failed to run filter-counts");
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(b"gene\tA\tB\nG1\t0\t5\nG2\t0\t3\n")
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(!output.status.success());
+    assert!(output.stdout.is_empty());
+}
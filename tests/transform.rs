@@ -0,0 +1,89 @@
+use std::fs;
+use std::process::{Command, Output};
+
+const DATA_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/data");
+
+// Read a tab-separated table (with a header) into rows of values, dropping the gene column:
+fn read_values(contents: &str) -> Vec<Vec<f64>> {
+    contents
+        .lines()
+        .skip(1)
+        .map(|l| l.split('\t').skip(1).map(|v| v.parse().unwrap()).collect())
+        .collect()
+}
+
+// Run filter-counts with the given transform on a test data file:
+fn transform(transform: &str, input: &str) -> Output {
+    let output = Command::new(env!("CARGO_BIN_EXE_filter-counts"))
+        .args(["-v", "--transform", transform])
+        .arg(format!("{}/{}", DATA_DIR, input))
+        .output()
+        .expect("failed to run filter-counts");
+    assert!(output.status.success());
+    output
+}
+
+// Check that two tables agree to a relative tolerance:
+fn assert_close(values: &[Vec<f64>], expected: &[Vec<f64>], tolerance: f64) {
+    assert_eq!(values.len(), expected.len());
+    for (row, expected_row) in values.iter().zip(expected.iter()) {
+        assert_eq!(row.len(), expected_row.len());
+        for (v, e) in row.iter().zip(expected_row.iter()) {
+            assert!((v - e).abs() <= tolerance * e.abs(), "{} != {}", v, e);
+        }
+    }
+}
+
+// The reference values differ from filter-counts' in the convergence criterion of the gamma GLM
+// (R's glm uses the change in deviance), so the parametric fit only agrees to ~1e-7:
+#[test]
+fn vst_matches_deseq2_parametric_fit() {
+    let output = transform("vst", "normalise_counts.tsv");
+    let expected = fs::read_to_string(format!("{}/vst_reference.tsv", DATA_DIR)).unwrap();
+    assert_close(
+        &read_values(&String::from_utf8(output.stdout).unwrap()),
+        &read_values(&expected),
+        1e-6,
+    );
+    assert!(!String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("using the mean dispersion"));
+}
+
+#[test]
+fn vst_falls_back_to_the_mean_dispersion() {
+    let output = transform("vst", "vst_mean_counts.tsv");
+    let expected = fs::read_to_string(format!("{}/vst_mean_reference.tsv", DATA_DIR)).unwrap();
+    assert_close(
+        &read_values(&String::from_utf8(output.stdout).unwrap()),
+        &read_values(&expected),
+        1e-10,
+    );
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("using the mean dispersion"));
+}
+
+#[test]
+fn log2cpm_is_log2_of_cpm_plus_the_pseudocount() {
+    let output = transform("log2cpm", "normalise_counts.tsv");
+    let counts =
+        read_values(&fs::read_to_string(format!("{}/normalise_counts.tsv", DATA_DIR)).unwrap());
+    let library_sizes: Vec<f64> = (0..counts[0].len())
+        .map(|j| counts.iter().map(|r| r[j]).sum())
+        .collect();
+    let expected: Vec<Vec<f64>> = counts
+        .iter()
+        .map(|r| {
+            r.iter()
+                .zip(library_sizes.iter())
+                .map(|(c, l)| (c / l * 1e6 + 1.0).log2())
+                .collect()
+        })
+        .collect();
+    assert_close(
+        &read_values(&String::from_utf8(output.stdout).unwrap()),
+        &expected,
+        1e-10,
+    );
+}